@@ -0,0 +1,188 @@
+use super::error::Error;
+use super::interface::Interface;
+use super::listener::Listener;
+use super::register::{Change, Registry};
+use super::responder::Responder;
+use super::types::{ChannelMessage, Instance};
+use dashmap::DashMap;
+use simple_dns::Packet;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::sleep;
+
+// RFC 6762 §8.3: at least two announcements, one second apart
+const ANNOUNCE_COUNT: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Announcer {
+    responder: Responder,
+    listener: Arc<Listener>,
+    // announcements still being repeated, keyed by name and interface index, 0 for every interface
+    pending: DashMap<(String, u32), AbortHandle>,
+    watcher: OnceLock<JoinHandle<()>>,
+}
+
+impl Announcer {
+    pub fn new(registry: &Registry, responder: Responder, listener: Arc<Listener>) -> Arc<Self> {
+        let mut changes = registry.subscribe();
+        let announcer = Arc::new(Announcer {
+            responder,
+            listener,
+            pending: DashMap::new(),
+            watcher: OnceLock::new(),
+        });
+        let announcer_clone = announcer.clone();
+        let watcher = tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(Change::Registered(instance)) => {
+                        announcer_clone.announce(instance, 0, Responder::announcement);
+                    }
+                    Ok(Change::Updated(instance)) => {
+                        // an initial announcement still in flight carries the old TXT, redo it whole
+                        if announcer_clone.is_pending(instance.name()) {
+                            announcer_clone.announce(instance, 0, Responder::announcement);
+                        } else {
+                            announcer_clone.announce(instance, 0, |responder, instance, _| {
+                                responder.txt_announcement(instance)
+                            });
+                        }
+                    }
+                    Ok(Change::Reprobed(instance, interface)) => {
+                        announcer_clone.announce(instance, interface, Responder::announcement);
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        eprintln!("Announcer missed {} registry changes", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        let _ = announcer.watcher.set(watcher);
+        announcer
+    }
+
+    fn is_pending(&self, name: &str) -> bool {
+        self.pending
+            .iter()
+            .any(|task| task.key().0 == name && !task.is_finished())
+    }
+
+    // stops the announcements of the name still being repeated
+    fn cancel(&self, name: &str) {
+        self.pending.retain(|(pending, _), task| {
+            if pending == name {
+                task.abort();
+            }
+            pending != name
+        });
+    }
+
+    // repeats the announcement of the instance in the background, built for every interface
+    // or only for the one with the index
+    fn announce(
+        self: &Arc<Self>,
+        instance: Instance,
+        interface: u32,
+        build: fn(&Responder, &Instance, &Interface) -> Packet<'static>,
+    ) {
+        let name = instance.name().to_string();
+        self.repeat(name, interface, move |responder, interface| {
+            build(responder, &instance, interface)
+        });
+    }
+
+    // Announces the address records of the interface, once the host name passed probing on it.
+    pub fn announce_host(self: &Arc<Self>, interface: &Interface) {
+        let name = super::mdns_hostname().clone();
+        self.repeat(name, interface.index, Responder::host_announcement);
+    }
+
+    fn repeat(
+        self: &Arc<Self>,
+        name: String,
+        interface: u32,
+        build: impl Fn(&Responder, &Interface) -> Packet<'static> + Send + Sync + 'static,
+    ) {
+        // an announcement on every interface replaces the ones on single interfaces
+        if interface == 0 {
+            self.cancel(&name);
+        }
+        let announcer = self.clone();
+        let key = (name.clone(), interface);
+        let task = tokio::spawn(async move {
+            for i in 0..ANNOUNCE_COUNT {
+                if i > 0 {
+                    sleep(ANNOUNCE_INTERVAL).await;
+                }
+                let sent = announcer
+                    .listener
+                    .multicast_each(interface, |interface| {
+                        let mut packet = build(&announcer.responder, interface);
+                        super::serialize_packet(&mut packet)
+                    })
+                    .await;
+                if let Err(e) = sent {
+                    eprintln!("Failed to announce {}: {}", name, e);
+                }
+            }
+        });
+        self.pending.retain(|_, task| !task.is_finished());
+        if let Some(previous) = self.pending.insert(key, task.abort_handle()) {
+            previous.abort();
+        }
+    }
+
+    async fn send(&self, mut packet: Packet<'_>) -> Result<(), Error> {
+        let bytes = super::serialize_packet(&mut packet).ok_or(Error::Encode("goodbye"))?;
+        self.listener.multicast(bytes).await
+    }
+
+    // Withdraws the records of an unregistered instance from the caches of our peers.
+    pub async fn goodbye(&self, instance: &Instance) -> Result<(), Error> {
+        // a late announcement would bring the instance right back
+        self.cancel(instance.name());
+        self.send(self.responder.goodbye(instance)).await
+    }
+
+    // Withdraws the address records of this host, once no instance is left to point at them.
+    pub async fn goodbye_host(&self) -> Result<(), Error> {
+        self.listener
+            .multicast_each(0, |interface| {
+                super::serialize_packet(&mut self.responder.host_goodbye(interface))
+            })
+            .await
+    }
+
+    // Withdraws addresses the interface lost from the caches of our peers.
+    pub async fn goodbye_addresses(&self, gone: &Interface) -> Result<(), Error> {
+        let mut packet = self.responder.host_goodbye(gone);
+        let bytes = super::serialize_packet(&mut packet).ok_or(Error::Encode("goodbye"))?;
+        let mut result = Ok(());
+        for (group, lost) in [
+            (super::multicast_addr_v4(), !gone.v4.is_empty()),
+            (super::multicast_addr_v6(), !gone.v6.is_empty()),
+        ] {
+            if lost {
+                let message = ChannelMessage {
+                    ip: *group,
+                    bytes: bytes.clone(),
+                    interface: gone.index,
+                };
+                result = result.and(self.listener.send(message).await);
+            }
+        }
+        result
+    }
+
+    // stops announcing registry changes
+    pub fn stop(&self) {
+        if let Some(watcher) = self.watcher.get() {
+            watcher.abort();
+        }
+        self.pending.iter().for_each(|task| task.abort());
+        self.pending.clear();
+    }
+}
@@ -1,163 +1,232 @@
 use bazuka::{SkmvCache, SkmvConfig};
 use dashmap::DashMap;
+use futures::Stream;
 use simple_dns::Name;
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinHandle;
 
 // Import Name type
+use super::announcer::Announcer;
+use super::browser::Browser;
 use super::cache::Cache;
 use super::cache::Tracker;
+use super::config::Config;
+use super::error::Error;
 use super::listener::Listener;
+use super::prober::Prober;
 use super::querier::Querier;
 use super::register::Registry;
+use super::resolver;
 use super::responder::Responder;
+use super::txt::TxtRecord;
 use super::types::*;
 
 /// HomeWeb API for managing devices in a home network via service discovery.
 pub struct HomeWeb {
     register: Registry,
+    responder: Responder,
+    prober: Prober,
+    announcer: Arc<Announcer>,
     listener: Arc<Listener>,
     querier: Arc<Querier>,
     cache: Cache,
+    // follows network changes, only available on Linux
+    watcher: Option<JoinHandle<()>>,
+    closed: bool,
 }
 
-impl HomeWeb {
-    async fn resolve_srv(&self, instance: String, duration: Duration) -> Option<(u16, String)> {
-        let query = Query {
-            qname: Name::new_unchecked(&instance).into_owned(),
-            qtype: QueryType::SRV,
-        };
-        // pick the first response
-        self.querier
-            .query(query, duration, false, &self.listener)
-            .await
-            .into_iter()
-            .next()
-            .and_then(|response| {
-                if let ResponseInner::SRV { port, target } = &response.inner {
-                    Some((*port, target.clone()))
-                } else {
-                    None
-                }
-            })
+// sends goodbyes for everything we own and stops every background task
+async fn teardown(
+    registry: Registry,
+    announcer: Arc<Announcer>,
+    querier: Arc<Querier>,
+    listener: Arc<Listener>,
+    watcher: Option<JoinHandle<()>>,
+) {
+    if let Some(watcher) = watcher {
+        watcher.abort();
     }
-    async fn resolve_txt(&self, instance: String, duration: Duration) -> HashMap<String, String> {
-        let query = Query {
-            qname: Name::new_unchecked(&instance).into_owned(),
-            qtype: QueryType::TXT,
-        };
-        // pick the first response
-        let mut map = HashMap::new();
-        self.querier
-            .query(query, duration, false, &self.listener)
-            .await
-            .iter()
-            .for_each(|response| {
-                if let ResponseInner::TXT { strings } = &response.inner {
-                    for string in strings {
-                        let parts: Vec<&str> = string.split('=').collect();
-                        if parts.len() == 2 {
-                            map.insert(parts[0].to_string(), parts[1].to_string());
-                        }
-                    }
-                }
-            });
-        map
+    announcer.stop();
+    let owned = registry.owned();
+    // releasing every claim also ends the tasks defending them
+    registry.clear();
+    for instance in &owned {
+        if let Err(e) = announcer.goodbye(instance).await {
+            eprintln!("Failed to send goodbye for {}: {}", instance.name(), e);
+        }
+    }
+    if !owned.is_empty()
+        && let Err(e) = announcer.goodbye_host().await
+    {
+        eprintln!("Failed to send host goodbye: {}", e);
     }
+    querier.stop();
+    listener.shutdown().await;
+}
 
-    async fn resolve_a(&self, hostname: String, duration: Duration) -> Option<Vec<Ipv4Addr>> {
-        let query = Query {
-            qname: Name::new_unchecked(&hostname).into_owned(),
-            qtype: QueryType::A,
-        };
+// TTLs and timeouts are whole seconds on the wire and in the cache, zero would expire at once
+const MIN_DURATION: Duration = Duration::from_secs(1);
 
-        let responses = self
-            .querier
-            .query(query, duration, false, &self.listener)
-            .await;
+/// Builder for a [`HomeWeb`] with tuned cache sizes, TTLs and worker counts.
+///
+/// Every setting left alone keeps the value [`HomeWeb::new`] uses.
+#[derive(Debug, Clone, Default)]
+pub struct HomeWebBuilder {
+    config: Config,
+}
 
-        let addresses = responses
-            .into_iter()
-            .filter_map(|response| {
-                if let ResponseInner::A { address } = response.inner {
-                    Some(address)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+impl HomeWebBuilder {
+    /// Number of names the cache holds records for (default 200).
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.config.cache_capacity = capacity;
+        self
+    }
 
-        if addresses.is_empty() {
-            None
-        } else {
-            Some(addresses)
-        }
+    /// Records cached per name and type, e.g. the addresses of a host (default 2).
+    pub fn cache_values_per_key(mut self, values: usize) -> Self {
+        self.config.cache_values_per_key = values;
+        self
     }
 
-    async fn resolve_aaaa(&self, hostname: String, duration: Duration) -> Option<Vec<Ipv6Addr>> {
-        let query = Query {
-            qname: Name::new_unchecked(&hostname).into_owned(),
-            qtype: QueryType::AAAA,
-        };
+    /// Drops cached names nobody looked up for this long (default 40 s, at least 1 s).
+    pub fn cache_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.cache_idle_timeout = timeout.max(MIN_DURATION);
+        self
+    }
 
-        let responses = self
-            .querier
-            .query(query, duration, false, &self.listener)
-            .await;
+    /// Upper bound on how long a name stays cached, whatever its records say (default 120 s,
+    /// at least 1 s).
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.config.cache_ttl = ttl.max(MIN_DURATION);
+        self
+    }
 
-        let addresses = responses
-            .into_iter()
-            .filter_map(|response| {
-                if let ResponseInner::AAAA { address } = response.inner {
-                    Some(address)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    /// TTL of the records we answer and announce with (default 120 s, at least 1 s).
+    ///
+    /// A zero TTL would turn every answer into a goodbye (RFC 6762 §10.1).
+    pub fn record_ttl(mut self, ttl: Duration) -> Self {
+        self.config.record_ttl = ttl.max(MIN_DURATION).as_secs().min(u32::MAX as u64) as u32;
+        self
+    }
 
-        if addresses.is_empty() {
-            None
-        } else {
-            Some(addresses)
-        }
+    /// How often cached records close to expiry are queried again (default 60 s, at least 1 s).
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.refresh_interval = interval.max(MIN_DURATION);
+        self
     }
-}
 
-impl HomeWeb {
-    pub fn new() -> Result<Self, String> {
+    /// Tasks answering incoming packets in parallel (default one per physical CPU).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers.max(1);
+        self
+    }
+
+    /// Received packets allowed to wait for a free worker (default 50).
+    pub fn work_queue(mut self, size: usize) -> Self {
+        self.config.work_queue = size.max(1);
+        self
+    }
+
+    /// Caches every response seen on the link from the start, see [`HomeWeb::set_passive_caching`].
+    pub fn passive_caching(mut self, enabled: bool) -> Self {
+        self.config.passive_caching = enabled;
+        self
+    }
+
+    /// Runs mDNS only on the named interface, can be called once per interface.
+    ///
+    /// Without any allowed interface every interface but loopback is used.
+    pub fn allow_interface(mut self, name: impl Into<String>) -> Self {
+        self.config.interfaces.allow.push(name.into());
+        self
+    }
+
+    /// Never runs mDNS on the named interface, e.g. a docker bridge.
+    pub fn deny_interface(mut self, name: impl Into<String>) -> Self {
+        self.config.interfaces.deny.push(name.into());
+        self
+    }
+
+    /// Opens a socket on every selected interface and starts the background tasks.
+    pub fn build(self) -> Result<HomeWeb, Error> {
+        let config = self.config;
         let registry = Registry::new();
-        let responder = Responder::new(registry.clone());
+        let responder = Responder::new(registry.clone(), config.record_ttl);
         let tracker: Tracker = Arc::new(DashMap::new());
-        let listener = Listener::new(tracker.clone(), responder)?;
+        let listener = Listener::new(tracker.clone(), responder.clone(), &config)?;
 
         let cache: Cache = Arc::new(SkmvCache::new(SkmvConfig {
-            idle_timeout: Some(40),
-            maximum_capacity: 200,
-            maximum_values_per_key: 2,
-            time_to_live: Some(120),
+            idle_timeout: Some(config.cache_idle_timeout.as_secs() as u32),
+            maximum_capacity: config.cache_capacity,
+            maximum_values_per_key: config.cache_values_per_key,
+            time_to_live: Some(config.cache_ttl.as_secs() as u32),
         }));
 
-        let querier = Querier::new(cache.clone(), tracker.clone(), listener.clone());
+        let querier = Querier::new(
+            cache.clone(),
+            tracker.clone(),
+            listener.clone(),
+            config.refresh_interval,
+        );
+        if config.passive_caching {
+            querier.set_passive(&listener, true);
+        }
+        let prober = Prober::new(registry.clone(), responder.clone(), listener.clone());
+        // announces instances as soon as the registry confirms them
+        let announcer = Announcer::new(&registry, responder.clone(), listener.clone());
+        #[cfg(target_os = "linux")]
+        let watcher = super::watcher::spawn(
+            config.interfaces.clone(),
+            registry.clone(),
+            prober.clone(),
+            announcer.clone(),
+            listener.clone(),
+        )
+        .inspect_err(|e| eprintln!("Failed to watch network changes: {}", e))
+        .ok();
+        #[cfg(not(target_os = "linux"))]
+        let watcher = None;
 
         Ok(HomeWeb {
             register: registry,
+            responder,
+            prober,
+            announcer,
             querier,
             listener,
             cache,
+            watcher,
+            closed: false,
         })
     }
+}
 
-    pub async fn get_devices(&self, svc_type: String, duration: Duration) -> Vec<String> {
+impl HomeWeb {
+    /// Creates a HomeWeb with the default settings, see [`HomeWeb::builder`] to tune them.
+    pub fn new() -> Result<Self, Error> {
+        Self::builder().build()
+    }
+
+    /// Starts configuring a HomeWeb, e.g. for networks with hundreds of devices.
+    pub fn builder() -> HomeWebBuilder {
+        HomeWebBuilder::default()
+    }
+
+    /// Looks up the instances of a service type, `mode` picks whether answers come back by unicast.
+    pub async fn get_devices(
+        &self,
+        svc_type: String,
+        duration: Duration,
+        mode: QueryMode,
+    ) -> Vec<String> {
         let query = Query {
             qname: Name::new_unchecked(&svc_type).into_owned(),
             qtype: QueryType::PTR,
         };
         let responses = self
             .querier
-            .query(query, duration, false, &self.listener)
+            .query(query, duration, false, mode, &self.listener)
             .await
             .iter()
             .filter_map(|response| {
@@ -172,58 +241,154 @@ impl HomeWeb {
         println!("Current cache: {:#?}", self.cache);
         responses
     }
+
+    /// Collects the service types other hosts on the link offer (RFC 6763 §9), e.g.
+    /// `_http._tcp.local`, for as long as `duration`. Our own service types aren't included.
+    pub async fn browse_service_types(&self, duration: Duration, mode: QueryMode) -> Vec<String> {
+        let query = Query {
+            qname: Name::new_unchecked(super::SERVICE_TYPES_QUERY).into_owned(),
+            qtype: QueryType::PTR,
+        };
+        let mut service_types: Vec<String> = self
+            .querier
+            .query(query, duration, false, mode, &self.listener)
+            .await
+            .iter()
+            .filter_map(|response| match &response.inner {
+                ResponseInner::PTR(service_type) => Some(service_type.clone()),
+                _ => None,
+            })
+            .collect();
+        service_types.sort();
+        service_types.dedup();
+        service_types
+    }
+
+    /// Opts into caching every response seen on the link instead of only the ones we queried for,
+    /// so lookups can often be answered straight from the cache.
+    pub fn set_passive_caching(&self, enabled: bool) {
+        self.querier.set_passive(&self.listener, enabled);
+    }
+
+    /// Counters of the records multicast in answers to queries and of those held back by the
+    /// once per second limit, useful to spot a host flooding the link with queries.
+    pub fn multicast_stats(&self) -> MulticastStats {
+        self.listener.multicast_stats()
+    }
+
+    /// Continuously browses for instances of the service type for as long as the stream is alive.
+    ///
+    /// With a `subtype` such as `_speaker` only the instances registered with that subtype are
+    /// reported (RFC 6763 §7.1). With [`QueryMode::UnicastFirst`] only the initial query asks
+    /// for unicast answers, the continuous queries after it are answered by multicast.
+    pub fn browse(
+        &self,
+        svc_type: String,
+        subtype: Option<String>,
+        mode: QueryMode,
+    ) -> impl Stream<Item = BrowseEvent> + Unpin + use<> {
+        let name = match subtype {
+            Some(subtype) => format!("{}._sub.{}", subtype, svc_type),
+            None => svc_type,
+        };
+        Browser::spawn(self.querier.clone(), self.listener.clone(), name, mode)
+    }
+
+    /// Resolves an instance to its port, host, addresses and metadata,
+    /// `mode` picks whether answers come back by unicast.
+    ///
+    /// The SRV, TXT and address questions go out together and the lookup returns as soon as
+    /// the SRV, the TXT and one address are known, a single address family is enough.
     pub async fn resolve_device(
         &self,
         instance_name: String,
         duration: Duration,
+        mode: QueryMode,
     ) -> Option<Device> {
-        // Split duration: 20% SRV, 30% TXT, 25% A, 25% AAAA
-        let dur_srv = duration.mul_f32(0.20);
-        let dur_txt = duration.mul_f32(0.30);
-        let dur_a = duration.mul_f32(0.25);
-        let dur_aaaa = duration.mul_f32(0.25);
-
-        // Step 1: Resolve SRV
-        let (port, target) = self.resolve_srv(instance_name.clone(), dur_srv).await?;
-
-        // Step 2: Resolve TXT
-        let txt = self.resolve_txt(instance_name.clone(), dur_txt).await;
-
-        // Step 3: Resolve A
-        let a_records = self
-            .resolve_a(target.clone(), dur_a)
-            .await?
-            .into_iter()
-            .map(std::net::IpAddr::V4)
-            .collect::<Vec<_>>();
-
-        // Step 4: Resolve AAAA
-        let aaaa_records = self
-            .resolve_aaaa(target.clone(), dur_aaaa)
-            .await?
-            .into_iter()
-            .map(std::net::IpAddr::V6)
-            .collect::<Vec<_>>();
-
-        // Debug: Print current cache
-        println!("Current cache: {:#?}", self.cache);
-
-        // Build and return Device
-        Some(Device {
-            name: instance_name,
-            port,
-            host: target,
-            metadata: txt,
-            addresses: [a_records, aaaa_records].concat(),
-        })
+        resolver::resolve(&self.querier, &self.listener, instance_name, duration, mode).await
     }
 
-    pub fn register_device(&mut self, instance: Instance) -> Result<(), String> {
-        self.register.register_device(instance);
-        Ok(())
+    /// Probes the instance name on the network and starts answering for it once it is unique.
+    ///
+    /// Conflicting names are renamed ("name-2", "name-3", ...) and the returned
+    /// [`Registration`] tracks the name the instance currently owns.
+    pub async fn register_device(&self, instance: Instance) -> Result<Registration, Error> {
+        self.prober.register(instance).await
     }
-    pub fn unregister_device(&mut self, instance: &Instance) -> Result<(), String> {
-        self.register.unregister_device(instance);
+    /// Stops answering for the instance and sends goodbyes so peers drop it right away.
+    pub async fn unregister_device(&self, registration: &Registration) -> Result<(), Error> {
+        // also ends a probe for a new name the handle doesn't know about yet
+        for claim in self.register.close(registration.id()) {
+            if claim.owned {
+                self.announcer.goodbye(&claim.instance).await?;
+            }
+        }
         Ok(())
     }
+
+    /// Replaces the TXT metadata of a registered instance and announces it to peers in place.
+    pub fn update_metadata(
+        &self,
+        registration: &Registration,
+        metadata: TxtRecord,
+    ) -> Result<(), Error> {
+        let instance = registration.instance().with_metadata(metadata.clone())?;
+        self.responder.check_size(&instance)?;
+        self.register.update(registration, metadata)
+    }
+
+    /// Sends goodbyes for every registered instance, stops all background tasks and closes the sockets.
+    pub async fn shutdown(mut self) {
+        self.closed = true;
+        teardown(
+            self.register.clone(),
+            self.announcer.clone(),
+            self.querier.clone(),
+            self.listener.clone(),
+            self.watcher.take(),
+        )
+        .await;
+    }
+}
+
+impl Drop for HomeWeb {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        // best effort, goodbyes only go out if the runtime keeps running for a moment
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(teardown(
+                self.register.clone(),
+                self.announcer.clone(),
+                self.querier.clone(),
+                self.listener.clone(),
+                self.watcher.take(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_durations_at_least_a_second() {
+        let config = HomeWeb::builder()
+            .record_ttl(Duration::ZERO)
+            .cache_ttl(Duration::from_millis(300))
+            .cache_idle_timeout(Duration::ZERO)
+            .refresh_interval(Duration::ZERO)
+            .config;
+        assert_eq!(config.record_ttl, 1);
+        assert_eq!(config.cache_ttl, MIN_DURATION);
+        assert_eq!(config.cache_idle_timeout, MIN_DURATION);
+        assert_eq!(config.refresh_interval, MIN_DURATION);
+
+        let config = HomeWeb::builder()
+            .record_ttl(Duration::from_secs(4500))
+            .config;
+        assert_eq!(config.record_ttl, 4500);
+    }
 }
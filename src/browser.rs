@@ -0,0 +1,252 @@
+use super::cache::{FEED_CAPACITY, Feed};
+use super::listener::Listener;
+use super::querier::Querier;
+use super::types::*;
+use futures::Stream;
+use rand::{Rng, rng};
+use simple_dns::Name;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+
+// RFC 6762 §5.2: continuous queries start one second apart and back off up to an hour
+const FIRST_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+// RFC 6762 §10.1: a goodbye removes the record one second after it arrived
+const GOODBYE_DELAY: Duration = Duration::from_secs(1);
+
+// what we know about a discovered instance
+struct Known {
+    expires_at: Instant,
+    // RFC 6762 §5.2: ask again at 80% of the PTR lifetime so it doesn't run out
+    refresh_at: Option<Instant>,
+    srv: Option<ResponseInner>,
+    txt: Option<ResponseInner>,
+}
+
+pub struct BrowseStream {
+    events: mpsc::Receiver<BrowseEvent>,
+}
+
+impl Stream for BrowseStream {
+    type Item = BrowseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+pub struct Browser {
+    querier: Arc<Querier>,
+    listener: Arc<Listener>,
+    service: Query,
+    mode: QueryMode,
+    feed: Feed,
+    records: mpsc::Receiver<Option<(Query, Response, u32)>>,
+    events: mpsc::Sender<BrowseEvent>,
+    known: HashMap<String, Known>,
+}
+
+impl Browser {
+    // Starts browsing the service type, or a "<subtype>._sub.<service type>" name,
+    // the task lives as long as the returned stream.
+    pub fn spawn(
+        querier: Arc<Querier>,
+        listener: Arc<Listener>,
+        service_type: String,
+        mode: QueryMode,
+    ) -> BrowseStream {
+        let (feed, records) = mpsc::channel(FEED_CAPACITY);
+        let (events, receiver) = mpsc::channel(32);
+        let service = Query {
+            qname: Name::new_unchecked(&service_type).into_owned(),
+            qtype: QueryType::PTR,
+        };
+        querier.track(&service, feed.clone());
+        let browser = Browser {
+            querier,
+            listener,
+            service,
+            mode,
+            feed,
+            records,
+            events,
+            known: HashMap::new(),
+        };
+        tokio::spawn(browser.run());
+        BrowseStream { events: receiver }
+    }
+
+    fn instance_queries(name: &str) -> [Query; 2] {
+        let qname = parse_name(name);
+        [
+            Query {
+                qname: qname.clone(),
+                qtype: QueryType::SRV,
+            },
+            Query {
+                qname,
+                qtype: QueryType::TXT,
+            },
+        ]
+    }
+
+    async fn emit(&self, event: BrowseEvent) {
+        // a dropped stream is noticed by the main loop
+        let _ = self.events.send(event).await;
+    }
+
+    async fn on_ptr(&mut self, name: String, ttl: u32) {
+        let now = Instant::now();
+        if ttl == 0 {
+            if let Some(known) = self.known.get_mut(&name) {
+                known.expires_at = now + GOODBYE_DELAY;
+                known.refresh_at = None;
+            }
+            return;
+        }
+        let lifetime = Duration::from_secs(ttl as u64);
+        // a little jitter keeps browsers on the link from refreshing in lockstep
+        let refresh_percent = rng().random_range(80..=82) as f64 / 100.0;
+        let refresh_at = Some(now + lifetime.mul_f64(refresh_percent));
+        if let Some(known) = self.known.get_mut(&name) {
+            known.expires_at = now + lifetime;
+            known.refresh_at = refresh_at;
+            return;
+        }
+        // follow the SRV and TXT of the instance so we can report updates
+        for query in Self::instance_queries(&name) {
+            self.querier.track(&query, self.feed.clone());
+        }
+        self.known.insert(
+            name.clone(),
+            Known {
+                expires_at: now + lifetime,
+                refresh_at,
+                srv: None,
+                txt: None,
+            },
+        );
+        self.emit(BrowseEvent::Added(name)).await;
+    }
+
+    async fn on_record(&mut self, query: Query, response: Response, ttl: u32) {
+        self.querier
+            .remember(query.clone(), response.clone(), ttl)
+            .await;
+        match (&query.qtype, response.inner) {
+            (QueryType::PTR, ResponseInner::PTR(name)) if query == self.service => {
+                self.on_ptr(name, ttl).await;
+            }
+            (QueryType::SRV | QueryType::TXT, inner) if ttl > 0 => {
+                let name = display_name(&query.qname);
+                let Some(known) = self.known.get_mut(&name) else {
+                    return;
+                };
+                let slot = if query.qtype == QueryType::SRV {
+                    &mut known.srv
+                } else {
+                    &mut known.txt
+                };
+                let changed = slot.as_ref().is_some_and(|old| *old != inner);
+                *slot = Some(inner);
+                if changed {
+                    self.emit(BrowseEvent::Updated(name)).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // drops instances whose PTR ran out or said goodbye, and refreshes the ones about to
+    async fn on_timer(&mut self, now: Instant) {
+        let expired: Vec<String> = self
+            .known
+            .iter()
+            .filter(|(_, known)| known.expires_at <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in expired {
+            self.known.remove(&name);
+            for query in Self::instance_queries(&name) {
+                self.querier.untrack(&query, &self.feed);
+            }
+            self.emit(BrowseEvent::Removed(name)).await;
+        }
+        let mut refresh = false;
+        for known in self.known.values_mut() {
+            if known.refresh_at.is_some_and(|at| at <= now) {
+                known.refresh_at = None;
+                refresh = true;
+            }
+        }
+        if refresh {
+            self.query(false).await;
+        }
+    }
+
+    async fn query(&self, first: bool) {
+        let unicast = self.mode.unicast(first);
+        if let Err(e) = self
+            .querier
+            .send_query(&self.service, unicast, &self.listener)
+            .await
+        {
+            eprintln!("Failed to send browse query: {}", e);
+        }
+    }
+
+    async fn run(mut self) {
+        // whatever the cache already learned counts as discovered
+        let now = SystemTime::now();
+        for response in self.querier.cached(&self.service).await {
+            if let ResponseInner::PTR(name) = &response.inner {
+                let remaining = response.ends_at.duration_since(now).unwrap_or_default();
+                self.on_ptr(name.clone(), remaining.as_secs() as u32).await;
+            }
+        }
+
+        // RFC 6762 §5.2: the first query goes out after a random 20-120 ms delay
+        let delay = Duration::from_millis(rng().random_range(20..=120));
+        let mut next_query = Instant::now() + delay;
+        let mut interval = FIRST_INTERVAL;
+        let mut first = true;
+        loop {
+            let deadline = self
+                .known
+                .values()
+                .flat_map(|known| [Some(known.expires_at), known.refresh_at])
+                .flatten()
+                .fold(next_query, Instant::min);
+            tokio::select! {
+                _ = self.events.closed() => break,
+                record = self.records.recv() => {
+                    if let Some(Some((query, response, ttl))) = record {
+                        self.on_record(query, response, ttl).await;
+                    }
+                }
+                _ = sleep_until(deadline) => {
+                    let now = Instant::now();
+                    if now >= next_query {
+                        self.query(first).await;
+                        first = false;
+                        next_query = now + interval;
+                        interval = (interval * 2).min(MAX_INTERVAL);
+                    }
+                    self.on_timer(now).await;
+                }
+            }
+        }
+
+        self.querier.untrack(&self.service, &self.feed);
+        for name in self.known.keys() {
+            for query in Self::instance_queries(name) {
+                self.querier.untrack(&query, &self.feed);
+            }
+        }
+    }
+}
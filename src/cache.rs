@@ -4,4 +4,8 @@ use dashmap::DashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 pub type Cache = Arc<SkmvCache<Query, Response>>;
-pub type Tracker = Arc<DashMap<Query, mpsc::Sender<Option<(Query, Response, u32)>>>>;
+// channel a tracked query receives the records of matching responses on, `None` ends it
+pub type Feed = mpsc::Sender<Option<(Query, Response, u32)>>;
+// room for the records of many responses at once, the listener never waits for a feed
+pub const FEED_CAPACITY: usize = 1024;
+pub type Tracker = Arc<DashMap<Query, Vec<Feed>>>;
@@ -0,0 +1,41 @@
+use super::interface::InterfaceFilter;
+use std::time::Duration;
+
+// knobs of a HomeWeb instance, the defaults are what HomeWeb::new uses
+#[derive(Debug, Clone)]
+pub struct Config {
+    // number of queries the cache holds records for
+    pub cache_capacity: usize,
+    // records kept per query, e.g. one A record per address of a host
+    pub cache_values_per_key: usize,
+    pub cache_idle_timeout: Duration,
+    pub cache_ttl: Duration,
+    // TTL of the records we answer with, in seconds
+    pub record_ttl: u32,
+    // how often cached records close to expiry are queried again
+    pub refresh_interval: Duration,
+    // tasks answering incoming packets in parallel
+    pub workers: usize,
+    // received packets waiting for a free worker
+    pub work_queue: usize,
+    pub passive_caching: bool,
+    // the interfaces mDNS runs on
+    pub interfaces: InterfaceFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache_capacity: 200,
+            cache_values_per_key: 2,
+            cache_idle_timeout: Duration::from_secs(40),
+            cache_ttl: Duration::from_secs(120),
+            record_ttl: 120,
+            refresh_interval: Duration::from_secs(60),
+            workers: num_cpus::get_physical(),
+            work_queue: 50,
+            passive_caching: false,
+            interfaces: InterfaceFilter::default(),
+        }
+    }
+}
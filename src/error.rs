@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+/// Errors returned by the HomeWeb API.
+#[derive(Debug)]
+pub enum Error {
+    /// No network interface is left after applying the allow and deny lists.
+    NoInterfaces,
+    /// Neither an IPv4 nor an IPv6 multicast socket could be opened on any interface.
+    Bind { v4: io::Error, v6: io::Error },
+    /// The host has no IPv4 socket to send the packet on.
+    NoIpv4,
+    /// The host has no IPv6 socket to send the packet on.
+    NoIpv6,
+    /// Sending a packet on the socket failed.
+    Send(io::Error),
+    /// Receiving on a socket failed.
+    Receive(io::Error),
+    /// A packet couldn't be encoded into a single message, names the packet.
+    Encode(&'static str),
+    /// The instance name or port isn't valid, with the reason.
+    InvalidInstance(String),
+    /// A TXT record attribute or the record as a whole isn't valid, with the reason.
+    InvalidTxtRecord(String),
+    /// The instance isn't (or is no longer) registered.
+    NotRegistered(String),
+    /// No instance is registered under the service type.
+    NoInstances(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoInterfaces => write!(f, "No network interface selected"),
+            Error::Bind { v4, v6 } => write!(
+                f,
+                "Failed to create both IPv4 and IPv6 sockets (IPv4: {}, IPv6: {})",
+                v4, v6
+            ),
+            Error::NoIpv4 => write!(f, "IPv4 socket not initialized"),
+            Error::NoIpv6 => write!(f, "IPv6 socket not initialized"),
+            Error::Send(e) => write!(f, "Failed to send message: {}", e),
+            Error::Receive(e) => write!(f, "Failed to receive message: {}", e),
+            Error::Encode(what) => write!(f, "Failed to prepare {}", what),
+            Error::InvalidInstance(reason) => write!(f, "Invalid instance: {}", reason),
+            Error::InvalidTxtRecord(reason) => write!(f, "Invalid TXT record: {}", reason),
+            Error::NotRegistered(name) => write!(f, "Instance not registered: {}", name),
+            Error::NoInstances(stype) => {
+                write!(f, "No instances found for service type: {}", stype)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { v4, .. } => Some(v4),
+            Error::Send(e) | Error::Receive(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// a network interface together with the addresses assigned to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    pub v4: Vec<Ipv4Addr>,
+    pub v6: Vec<Ipv6Addr>,
+}
+
+impl Interface {
+    pub fn is_loopback(&self) -> bool {
+        self.v4.iter().any(|ip| ip.is_loopback()) || self.v6.iter().any(|ip| ip.is_loopback())
+    }
+}
+
+#[cfg(unix)]
+fn index_of(name: &str) -> u32 {
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return 0;
+    };
+    // SAFETY: the pointer is a valid nul terminated string for the duration of the call
+    unsafe { libc::if_nametoindex(name.as_ptr()) }
+}
+
+#[cfg(not(unix))]
+fn index_of(_name: &str) -> u32 {
+    0
+}
+
+// every interface of the host which has at least one address
+pub fn list() -> Vec<Interface> {
+    let mut interfaces: BTreeMap<String, Interface> = BTreeMap::new();
+    for (name, ip) in local_ip_address::list_afinet_netifas().unwrap_or_default() {
+        let interface = interfaces.entry(name.clone()).or_insert_with(|| Interface {
+            index: index_of(&name),
+            name,
+            v4: Vec::new(),
+            v6: Vec::new(),
+        });
+        match ip {
+            IpAddr::V4(v4) => interface.v4.push(v4),
+            IpAddr::V6(v6) => interface.v6.push(v6),
+        }
+    }
+    interfaces.into_values().collect()
+}
+
+// What changed between two snapshots of the selected interfaces.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    // interfaces which showed up or gained an address, our records have to be probed and
+    // announced on them again
+    pub added: Vec<Interface>,
+    // per interface still around, the addresses it lost
+    pub removed: Vec<Interface>,
+}
+
+pub fn diff(old: &[Interface], new: &[Interface]) -> Changes {
+    let mut changes = Changes::default();
+    for interface in new {
+        let Some(before) = old.iter().find(|i| i.index == interface.index) else {
+            changes.added.push(interface.clone());
+            continue;
+        };
+        if interface.v4.iter().any(|ip| !before.v4.contains(ip))
+            || interface.v6.iter().any(|ip| !before.v6.contains(ip))
+        {
+            changes.added.push(interface.clone());
+        }
+        let gone = Interface {
+            v4: before
+                .v4
+                .iter()
+                .filter(|ip| !interface.v4.contains(ip))
+                .copied()
+                .collect(),
+            v6: before
+                .v6
+                .iter()
+                .filter(|ip| !interface.v6.contains(ip))
+                .copied()
+                .collect(),
+            ..interface.clone()
+        };
+        if !gone.v4.is_empty() || !gone.v6.is_empty() {
+            changes.removed.push(gone);
+        }
+    }
+    changes
+}
+
+// Which interfaces mDNS runs on, by name.
+//
+// With an empty allow list every interface but loopback is used,
+// the deny list always wins.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn selects(&self, interface: &Interface) -> bool {
+        if self.deny.contains(&interface.name) {
+            return false;
+        }
+        if self.allow.is_empty() {
+            return !interface.is_loopback();
+        }
+        self.allow.contains(&interface.name)
+    }
+
+    pub fn select(&self, interfaces: Vec<Interface>) -> Vec<Interface> {
+        interfaces
+            .into_iter()
+            .filter(|interface| self.selects(interface))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, v4: Ipv4Addr) -> Interface {
+        Interface {
+            name: name.to_string(),
+            index: 0,
+            v4: vec![v4],
+            v6: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_interface_filter() {
+        let lo = interface("lo", Ipv4Addr::LOCALHOST);
+        let lan = interface("eth0", Ipv4Addr::new(192, 168, 1, 2));
+        let docker = interface("docker0", Ipv4Addr::new(172, 17, 0, 1));
+
+        // everything but loopback by default
+        let filter = InterfaceFilter::default();
+        assert!(!filter.selects(&lo));
+        assert!(filter.selects(&lan) && filter.selects(&docker));
+
+        let filter = InterfaceFilter {
+            allow: Vec::new(),
+            deny: vec!["docker0".to_string()],
+        };
+        assert!(filter.selects(&lan) && !filter.selects(&docker));
+
+        // an allow list picks exactly the named interfaces, loopback included
+        let filter = InterfaceFilter {
+            allow: vec!["lo".to_string(), "docker0".to_string()],
+            deny: vec!["docker0".to_string()],
+        };
+        assert!(filter.selects(&lo));
+        assert!(!filter.selects(&lan) && !filter.selects(&docker));
+    }
+
+    #[test]
+    fn test_interface_diff() {
+        let lan = [interface("eth0", Ipv4Addr::new(192, 168, 1, 2))];
+        assert_eq!(diff(&lan, &lan), Changes::default());
+
+        // a new DHCP lease replaces the address
+        let mut renewed = interface("eth0", Ipv4Addr::new(192, 168, 1, 7));
+        renewed.index = lan[0].index;
+        let changes = diff(&lan, &[renewed.clone()]);
+        assert_eq!(changes.added, [renewed]);
+        assert_eq!(changes.removed.len(), 1);
+        assert_eq!(changes.removed[0].v4, lan[0].v4);
+
+        // an interface going away leaves nothing to send goodbyes on
+        let mut wifi = interface("wlan0", Ipv4Addr::new(10, 0, 0, 2));
+        wifi.index = 3;
+        let both = [lan[0].clone(), wifi.clone()];
+        assert_eq!(diff(&both, &lan), Changes::default());
+        // only the new interface has to be probed on
+        assert_eq!(diff(&lan, &both).added, [wifi]);
+    }
+}
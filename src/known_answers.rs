@@ -0,0 +1,127 @@
+use rand::{Rng, rng};
+use simple_dns::{Packet, PacketFlag, ResourceRecord};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// RFC 6762 §7.2: responders wait 400-500 ms for the rest of a truncated known-answer list
+const COLLECT_DELAY_MS: std::ops::RangeInclusive<u64> = 400..=500;
+
+pub fn collect_delay() -> Duration {
+    Duration::from_millis(rng().random_range(COLLECT_DELAY_MS))
+}
+
+// the parsed packet borrows the receive buffer, this copy can wait for its continuation
+fn owned(packet: &Packet) -> Packet<'static> {
+    let mut owned = Packet::new_query(packet.id());
+    owned.questions = packet
+        .questions
+        .iter()
+        .map(|q| q.clone().into_owned())
+        .collect();
+    owned.answers = owned_records(&packet.answers);
+    owned.name_servers = owned_records(&packet.name_servers);
+    owned.additional_records = owned_records(&packet.additional_records);
+    owned
+}
+
+fn owned_records(records: &[ResourceRecord]) -> Vec<ResourceRecord<'static>> {
+    records.iter().map(|r| r.clone().into_owned()).collect()
+}
+
+// Queries with the TC bit set, merged with the known-answer packets their sender follows up with.
+#[derive(Debug, Default)]
+pub struct KnownAnswers {
+    // per sender and interface, the generation of the latest packet and the query so far
+    pending: Mutex<HashMap<(SocketAddr, u32), (u64, Packet<'static>)>>,
+    generation: AtomicU64,
+}
+
+impl KnownAnswers {
+    // Holds back a truncated query or adds a continuation to one, returning the generation to
+    // answer with once the sender went quiet. `None` for packets of neither kind.
+    pub fn absorb(&self, source: SocketAddr, interface: u32, packet: &Packet) -> Option<u64> {
+        let key = (source, interface);
+        let mut pending = self.pending.lock().unwrap();
+        if packet.has_flags(PacketFlag::TRUNCATION) && !packet.questions.is_empty() {
+            let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+            pending.insert(key, (generation, owned(packet)));
+            return Some(generation);
+        }
+        // continuations carry no questions, only more known answers
+        if !packet.questions.is_empty() {
+            return None;
+        }
+        let (latest, query) = pending.get_mut(&key)?;
+        *latest = self.generation.fetch_add(1, Ordering::Relaxed);
+        query.answers.extend(owned_records(&packet.answers));
+        Some(*latest)
+    }
+
+    // the merged query, unless another packet of its sender arrived after the generation
+    pub fn take(
+        &self,
+        source: SocketAddr,
+        interface: u32,
+        generation: u64,
+    ) -> Option<Packet<'static>> {
+        let mut pending = self.pending.lock().unwrap();
+        let key = (source, interface);
+        if pending.get(&key)?.0 != generation {
+            return None;
+        }
+        pending.remove(&key).map(|(_, query)| query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_dns::{CLASS, Name, QCLASS, QTYPE, Question, TYPE, rdata::*};
+
+    fn known_answer(target: &'static str) -> ResourceRecord<'static> {
+        ResourceRecord::new(
+            Name::new_unchecked("_homecast._tcp.local"),
+            CLASS::IN,
+            120,
+            RData::PTR(PTR(Name::new_unchecked(target))),
+        )
+    }
+
+    #[test]
+    fn test_truncated_known_answers_merge() {
+        let source: SocketAddr = "192.168.1.5:5353".parse().unwrap();
+        let collector = KnownAnswers::default();
+
+        let mut query = Packet::new_query(0);
+        query.set_flags(PacketFlag::TRUNCATION);
+        query.questions.push(Question::new(
+            Name::new_unchecked("_homecast._tcp.local"),
+            QTYPE::TYPE(TYPE::PTR),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        ));
+        query.answers.push(known_answer("tv._homecast._tcp.local"));
+        let first = collector.absorb(source, 2, &query).unwrap();
+
+        let mut rest = Packet::new_query(0);
+        rest.answers
+            .push(known_answer("radio._homecast._tcp.local"));
+        let last = collector.absorb(source, 2, &rest).unwrap();
+        // the same continuation from another host isn't part of it
+        assert!(
+            collector
+                .absorb("192.168.1.6:5353".parse().unwrap(), 2, &rest)
+                .is_none()
+        );
+
+        // only the timer of the last packet answers
+        assert!(collector.take(source, 2, first).is_none());
+        let merged = collector.take(source, 2, last).unwrap();
+        assert_eq!(merged.questions.len(), 1);
+        assert_eq!(merged.answers.len(), 2);
+        assert!(collector.take(source, 2, last).is_none());
+    }
+}
@@ -1,23 +1,35 @@
 use rand::{Rng, distr, rng};
-use simple_dns::{
-    Packet, ResourceRecord,
-    rdata::{RData, TXT},
-};
+use simple_dns::{Name, Packet, ResourceRecord, rdata::RData};
 use std::net::SocketAddr;
 use std::sync::OnceLock;
 use std::time::{Duration, SystemTime};
 use types::*;
 
+mod announcer;
 mod api;
+mod browser;
 mod cache;
+mod config;
+mod error;
+mod interface;
+mod known_answers;
+mod limiter;
 mod listener;
+mod prober;
 mod querier;
 mod register;
+mod resolver;
 mod responder;
+mod scheduler;
+mod txt;
 mod types;
+#[cfg(target_os = "linux")]
+mod watcher;
 
-pub use api::HomeWeb;
-pub use types::Instance;
+pub use api::{HomeWeb, HomeWebBuilder};
+pub use error::Error;
+pub use txt::TxtRecord;
+pub use types::{BrowseEvent, Instance, MulticastStats, QueryMode, Registration};
 
 macro_rules! global {
     ($static_name:ident, $fn_name:ident, $type:ty, $init:expr) => {
@@ -42,6 +54,9 @@ global!(
     "[ff02::fb]:5353".parse().unwrap()
 );
 
+// RFC 6763 §9: PTR records under this name point at every service type on the link
+const SERVICE_TYPES_QUERY: &str = "_services._dns-sd._udp.local";
+
 global!(HOSTNAME, mdns_hostname, String, {
     let hostname = gethostname::gethostname().to_string_lossy().into_owned();
     let mut sanitized = String::with_capacity(hostname.len());
@@ -77,92 +92,91 @@ pub fn random_alphanumeric_string(len: usize) -> String {
 fn prepare_triplet_from_record<'a>(record: &ResourceRecord<'a>) -> Option<(Query, Response, u32)> {
     let name = record.name.clone().into_owned();
     let ttl = record.ttl;
-    let ends_at = SystemTime::now() + Duration::from_secs(ttl as u64);
+    let received_at = SystemTime::now();
+    let ends_at = received_at + Duration::from_secs(ttl as u64);
 
-    match &record.rdata {
-        RData::PTR(ptr) => Some((
-            Query {
-                qname: name,
-                qtype: QueryType::PTR,
-            },
-            Response {
-                inner: ResponseInner::PTR(ptr.to_string()),
-                ends_at,
-            },
-            ttl,
-        )),
-        RData::SRV(srv) => Some((
-            Query {
-                qname: name,
-                qtype: QueryType::SRV,
-            },
-            Response {
-                inner: ResponseInner::SRV {
-                    port: srv.port,
-                    target: srv.target.to_string(),
-                },
-                ends_at,
-            },
-            ttl,
-        )),
-        RData::TXT(txt) => Some((
-            Query {
-                qname: name,
-                qtype: QueryType::TXT,
+    let (qtype, inner) = match &record.rdata {
+        RData::PTR(ptr) => (QueryType::PTR, ResponseInner::PTR(types::display_name(ptr))),
+        RData::SRV(srv) => (
+            QueryType::SRV,
+            ResponseInner::SRV {
+                port: srv.port,
+                target: types::display_name(&srv.target),
             },
-            Response {
-                inner: ResponseInner::TXT {
-                    strings: txt
-                        .attributes()
-                        .into_iter()
-                        .filter_map(|(k, v)| v.map(|val| format!("{}={}", k, val)))
-                        .collect(),
-                },
-                ends_at,
+        ),
+        RData::TXT(_) => (
+            QueryType::TXT,
+            ResponseInner::TXT(TxtRecord::from_wire(&rdata_bytes(record))),
+        ),
+        RData::A(a) => (
+            QueryType::A,
+            ResponseInner::A {
+                address: a.address.into(),
             },
-            ttl,
-        )),
-        RData::A(a) => Some((
-            Query {
-                qname: name,
-                qtype: QueryType::A,
+        ),
+        RData::AAAA(aaaa) => (
+            QueryType::AAAA,
+            ResponseInner::AAAA {
+                address: aaaa.address.into(),
             },
-            Response {
-                inner: ResponseInner::A {
-                    address: a.address.into(),
-                },
-                ends_at,
-            },
-            ttl,
-        )),
-        RData::AAAA(aaaa) => Some((
-            Query {
-                qname: name,
-                qtype: QueryType::AAAA,
-            },
-            Response {
-                inner: ResponseInner::AAAA {
-                    address: aaaa.address.into(),
-                },
-                ends_at,
-            },
-            ttl,
-        )),
-        _ => None,
-    }
+        ),
+        _ => return None,
+    };
+    Some((
+        Query { qname: name, qtype },
+        Response {
+            inner,
+            ends_at,
+            received_at,
+            cache_flush: record.cache_flush,
+        },
+        ttl,
+    ))
 }
 
-fn form_text_record(metadata: &Vec<String>) -> TXT<'static> {
-    let mut text_data = TXT::new();
-    metadata.iter().for_each(|pair_string| {
-        _ = text_data.add_string(pair_string);
-    });
-    text_data.into_owned()
+// wire format of the rdata alone, used for comparing records byte by byte
+fn rdata_bytes(record: &ResourceRecord) -> Vec<u8> {
+    // written under the root name, the rdata starts after the 12 byte header,
+    // the single root label byte and the 10 byte type/class/ttl/length block
+    let mut packet = Packet::new_reply(0);
+    packet.answers.push(ResourceRecord::new(
+        Name::new_unchecked(""),
+        record.class,
+        0,
+        record.rdata.clone(),
+    ));
+    packet
+        .build_bytes_vec()
+        .map(|bytes| bytes[23..].to_vec())
+        .unwrap_or_default()
+}
+
+// the fixed DNS header in front of every message
+const HEADER_SIZE: usize = 12;
+
+// encoded size of a single record, names aren't compressed so the sizes of records add up
+fn record_size(record: &ResourceRecord) -> Option<usize> {
+    let mut packet = Packet::new_query(0);
+    packet.answers.push(record.clone());
+    let bytes = packet.build_bytes_vec().ok()?;
+    Some(bytes.len() - HEADER_SIZE)
+}
+
+// the largest mDNS message that fits into a single Ethernet frame
+const MAX_PACKET_SIZE: usize = 1472;
+// RFC 6762 §17: 9000 bytes including the IP and UDP headers, the IPv6 ones being the larger
+const MAX_MESSAGE_SIZE: usize = 9000 - 48;
+
+// size of the message as we send it, RFC 6762 §18.14: with names compressed
+fn message_size(packet: &Packet) -> Option<usize> {
+    packet
+        .build_bytes_vec_compressed()
+        .ok()
+        .map(|bytes| bytes.len())
 }
 
 fn reduce_packet_size(packet: &mut Packet, max_size: usize) -> bool {
-    let mut bytes = Vec::new();
-    while packet.write_to(&mut bytes).is_err() || bytes.len() > max_size {
+    while message_size(packet).is_none_or(|size| size > max_size) {
         if !packet.additional_records.is_empty() {
             packet.additional_records.pop();
         } else if !packet.answers.is_empty() {
@@ -170,22 +184,28 @@ fn reduce_packet_size(packet: &mut Packet, max_size: usize) -> bool {
         } else {
             return false;
         }
-        bytes.clear();
     }
     true
 }
 
-fn serialize_packet<'a>(packet: &'a mut Packet<'a>) -> Option<Vec<u8>> {
+fn serialize_packet(packet: &mut Packet) -> Option<Vec<u8>> {
+    // RFC 6762 §17: a record too large for a packet of its own is sent in a larger,
+    // fragmented message instead of being left out
+    let oversized = packet
+        .answers
+        .iter()
+        .chain(&packet.name_servers)
+        .any(|record| record_size(record).is_none_or(|size| HEADER_SIZE + size > MAX_PACKET_SIZE));
+    let max_size = if oversized {
+        MAX_MESSAGE_SIZE
+    } else {
+        MAX_PACKET_SIZE
+    };
     // If you have to remove all answers and additional records for reduction, return None
-    if !reduce_packet_size(packet, 1472) {
+    if !reduce_packet_size(packet, max_size) {
         return None;
     };
 
     // Serialize the packet to bytes
-    let mut response_bytes = Vec::new();
-    if packet.write_to(&mut response_bytes).is_ok() {
-        Some(response_bytes)
-    } else {
-        None
-    }
+    packet.build_bytes_vec_compressed().ok()
 }
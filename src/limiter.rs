@@ -0,0 +1,128 @@
+use super::types::MulticastStats;
+use simple_dns::ResourceRecord;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// RFC 6762 §6: a record isn't multicast on an interface more than once per second
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+// (interface, v6, lowercased name, type, rdata) identifies a record multicast on a link
+type RecordKey = (u32, bool, String, u16, Vec<u8>);
+
+fn record_key(interface: u32, v6: bool, record: &ResourceRecord) -> RecordKey {
+    (
+        interface,
+        v6,
+        record.name.to_string().to_lowercase(),
+        u16::from(record.rdata.type_code()),
+        super::rdata_bytes(record),
+    )
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_multicast: HashMap<RecordKey, Instant>,
+    stats: MulticastStats,
+}
+
+// Remembers when each record was last multicast per interface, so a host flooding us
+// with queries doesn't get us to flood the link with the same answers.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    // Drops the records multicast on the link within the last second and marks the rest as sent.
+    // Answers defending our names against a probe always go out (§8.1), they are only recorded.
+    pub fn admit(
+        &self,
+        interface: u32,
+        v6: bool,
+        records: &mut Vec<ResourceRecord<'_>>,
+        probe_defense: bool,
+    ) {
+        self.admit_at(interface, v6, records, probe_defense, Instant::now());
+    }
+
+    fn admit_at(
+        &self,
+        interface: u32,
+        v6: bool,
+        records: &mut Vec<ResourceRecord<'_>>,
+        probe_defense: bool,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .last_multicast
+            .retain(|_, sent| now.duration_since(*sent) < MIN_INTERVAL);
+        let before = records.len();
+        records.retain(|record| {
+            let key = record_key(interface, v6, record);
+            if !probe_defense && state.last_multicast.contains_key(&key) {
+                return false;
+            }
+            state.last_multicast.insert(key, now);
+            true
+        });
+        state.stats.sent += records.len() as u64;
+        state.stats.suppressed += (before - records.len()) as u64;
+    }
+
+    pub fn stats(&self) -> MulticastStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_dns::{CLASS, Name, rdata::*};
+
+    fn ptr(name: &'static str, target: &'static str) -> ResourceRecord<'static> {
+        ResourceRecord::new(
+            Name::new_unchecked(name),
+            CLASS::IN,
+            120,
+            RData::PTR(PTR(Name::new_unchecked(target))),
+        )
+    }
+
+    #[test]
+    fn test_rate_limit_per_record_and_interface() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        let tv = ptr("_homecast._tcp.local", "tv._homecast._tcp.local");
+        let radio = ptr("_homecast._tcp.local", "radio._homecast._tcp.local");
+
+        let mut records = vec![tv.clone()];
+        limiter.admit_at(2, false, &mut records, false, now);
+        assert_eq!(records.len(), 1);
+
+        // the same record within a second is held back, other records and interfaces aren't
+        let mut records = vec![tv.clone(), radio];
+        limiter.admit_at(2, false, &mut records, false, now);
+        assert_eq!(records.len(), 1);
+        let mut records = vec![tv.clone()];
+        limiter.admit_at(3, false, &mut records, false, now);
+        assert_eq!(records.len(), 1);
+
+        // defending against a probe isn't limited
+        let mut records = vec![tv.clone()];
+        limiter.admit_at(2, false, &mut records, true, now);
+        assert_eq!(records.len(), 1);
+
+        let mut records = vec![tv];
+        limiter.admit_at(2, false, &mut records, false, now + MIN_INTERVAL);
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            limiter.stats(),
+            MulticastStats {
+                sent: 5,
+                suppressed: 1
+            }
+        );
+    }
+}
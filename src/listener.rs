@@ -1,24 +1,59 @@
-use super::cache::Tracker;
+use super::cache::{Feed, Tracker};
+use super::config::Config;
+use super::error::Error;
+use super::interface::{self, Interface};
+use super::known_answers::{self, KnownAnswers};
+use super::querier::AskedQuestions;
 use super::responder::Responder;
-use super::types::{ChannelMessage, Query, Response};
-use simple_dns::{CLASS, Packet, PacketFlag, Question};
+use super::scheduler::Scheduler;
+use super::types::{ChannelMessage, MulticastStats, Query, Response};
+use simple_dns::{CLASS, OPCODE, Packet, PacketFlag, Question, RCODE};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
+    collections::HashMap,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    sync::Arc,
+    sync::{Arc, Mutex, OnceLock, RwLock},
 };
 use tokio::{
     net::UdpSocket,
-    sync::{OnceCell, mpsc},
+    sync::mpsc::{self, error::TrySendError},
+    task::{AbortHandle, JoinHandle},
+    time::sleep,
 };
 
+// a socket sending and receiving mDNS on a single interface
+#[derive(Debug)]
+struct Link {
+    // the interface with its addresses as of the last sync
+    interface: Mutex<Interface>,
+    index: u32,
+    v6: bool,
+    // the IPv4 address the group was joined on, the link is reopened once it goes away
+    address: Option<Ipv4Addr>,
+    socket: Arc<UdpSocket>,
+    // the task receiving on the socket
+    receiver: OnceLock<AbortHandle>,
+}
+
 #[derive(Debug)]
 pub struct Listener {
-    v4_socket: Arc<Option<UdpSocket>>,
-    v6_socket: Arc<Option<UdpSocket>>,
+    // one socket per selected interface and address family
+    links: RwLock<Vec<Arc<Link>>>,
     tracker: Tracker,
     responder: Responder,
-    poison: OnceCell<Arc<Listener>>,
+    // number of workers, received packets queue up for them here
+    workers: usize,
+    work_giver: async_channel::Sender<ChannelMessage>,
+    // the receive loops and their workers, aborted on shutdown
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    // in passive mode every response on the link is fed here, not only the tracked ones
+    passive: Mutex<Option<Feed>>,
+    // holds back multicast answers with shared records to aggregate them
+    scheduler: Scheduler,
+    // multicast questions of other hosts, for duplicate question suppression
+    asked: AskedQuestions,
+    // truncated queries waiting for the rest of their known answers
+    known_answers: KnownAnswers,
 }
 
 // here we will write socket helper functions
@@ -29,168 +64,435 @@ impl Listener {
         Ok(())
     }
 
-    fn set_v4_multicast_options(msock: &Socket) -> Result<(), std::io::Error> {
+    fn set_v4_multicast_options(msock: &Socket, address: &Ipv4Addr) -> Result<(), std::io::Error> {
         // Disable multicast loopback during production
         msock.set_multicast_loop_v4(false)?;
         msock.set_ttl(255)?;
+        msock.set_multicast_ttl_v4(255)?;
+        // only deliver packets of the groups joined on this socket's interface
+        #[cfg(target_os = "linux")]
+        msock.set_multicast_all_v4(false)?;
 
         let bind_addr: SocketAddrV4 = "0.0.0.0:5353".parse().unwrap();
         msock.bind(&SockAddr::from(bind_addr))?;
 
         let multicast_addr_v4: Ipv4Addr = "224.0.0.251".parse().unwrap();
-        msock.join_multicast_v4(&multicast_addr_v4, &Ipv4Addr::UNSPECIFIED)?;
+        msock.join_multicast_v4(&multicast_addr_v4, address)?;
+        msock.set_multicast_if_v4(address)?;
+        #[cfg(target_os = "linux")]
+        Self::enable_pktinfo(msock, libc::IPPROTO_IP, libc::IP_PKTINFO)?;
         Ok(())
     }
 
-    fn set_v6_multicast_options(msock: &Socket) -> Result<(), std::io::Error> {
+    fn set_v6_multicast_options(msock: &Socket, index: u32) -> Result<(), std::io::Error> {
+        // the IPv4 sockets take care of IPv4 traffic
+        msock.set_only_v6(true)?;
         // Disable multicast loopback during production
         msock.set_multicast_loop_v6(false)?;
         msock.set_unicast_hops_v6(255)?; // For unicast
         msock.set_multicast_hops_v6(255)?; // For multicast
+        #[cfg(target_os = "linux")]
+        msock.set_multicast_all_v6(false)?;
 
         let bind_addr: SocketAddrV6 = "[::]:5353".parse().unwrap();
         msock.bind(&SockAddr::from(bind_addr))?;
 
         let multicast_addr_v6: Ipv6Addr = "ff02::fb".parse().unwrap();
-        msock.join_multicast_v6(&multicast_addr_v6, 0)?;
+        msock.join_multicast_v6(&multicast_addr_v6, index)?;
+        msock.set_multicast_if_v6(index)?;
+        #[cfg(target_os = "linux")]
+        Self::enable_pktinfo(msock, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
         Ok(())
     }
 
-    fn get_v4_msocket() -> Result<UdpSocket, std::io::Error> {
+    // asks the kernel to tell which interface every packet arrived on
+    #[cfg(target_os = "linux")]
+    fn enable_pktinfo(msock: &Socket, level: i32, option: i32) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+        let enable: libc::c_int = 1;
+        // SAFETY: the option value is a c_int living for the duration of the call
+        let result = unsafe {
+            libc::setsockopt(
+                msock.as_raw_fd(),
+                level,
+                option,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Receives a packet along with the index of the interface it arrived on (RFC 6762 §6.2).
+    #[cfg(target_os = "linux")]
+    async fn recv_from_interface(
+        link: &Link,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, u32), std::io::Error> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+        let fd = link.socket.as_raw_fd();
+        link.socket
+            .async_io(Interest::READABLE, || {
+                // SAFETY: every pointer handed to recvmsg points into locals outliving the call,
+                // the control messages are only read within the length the kernel reported
+                unsafe {
+                    let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+                    let mut iov = libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    };
+                    let mut control = [0u64; 16];
+                    let mut msg: libc::msghdr = std::mem::zeroed();
+                    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+                    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as u32;
+                    msg.msg_iov = &mut iov;
+                    msg.msg_iovlen = 1;
+                    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                    msg.msg_controllen = std::mem::size_of_val(&control);
+                    let len = libc::recvmsg(fd, &mut msg, 0);
+                    if len < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    let mut index = link.index;
+                    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    while !cmsg.is_null() {
+                        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                                let info = std::ptr::read_unaligned(
+                                    libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo
+                                );
+                                index = info.ipi_ifindex as u32;
+                            }
+                            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                                let info = std::ptr::read_unaligned(
+                                    libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo
+                                );
+                                index = info.ipi6_ifindex;
+                            }
+                            _ => {}
+                        }
+                        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                    }
+                    let source = SockAddr::new(addr, msg.msg_namelen)
+                        .as_socket()
+                        .ok_or_else(|| std::io::Error::other("Unexpected source address"))?;
+                    Ok((len as usize, source, index))
+                }
+            })
+            .await
+    }
+
+    // without packet info the interface of the socket is the best guess
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_from_interface(
+        link: &Link,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, u32), std::io::Error> {
+        let (len, source) = link.socket.recv_from(buf).await?;
+        Ok((len, source, link.index))
+    }
+
+    fn get_v4_msocket(address: &Ipv4Addr) -> Result<UdpSocket, std::io::Error> {
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
         Self::set_common_options(&socket)?;
-        Self::set_v4_multicast_options(&socket)?;
+        Self::set_v4_multicast_options(&socket, address)?;
         UdpSocket::from_std(socket.into())
             .map_err(|e| std::io::Error::new(e.kind(), format!("Tokio conversion failed: {}", e)))
     }
 
-    fn get_v6_msocket() -> Result<UdpSocket, std::io::Error> {
+    fn get_v6_msocket(index: u32) -> Result<UdpSocket, std::io::Error> {
         let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
         Self::set_common_options(&socket)?;
-        Self::set_v6_multicast_options(&socket)?;
+        Self::set_v6_multicast_options(&socket, index)?;
         UdpSocket::from_std(socket.into())
             .map_err(|e| std::io::Error::new(e.kind(), format!("Tokio conversion failed: {}", e)))
     }
+
+    // Opens the socket of one address family on the interface, None if it has no such address.
+    fn open_link(interface: &Interface, v6: bool) -> Option<Result<Link, std::io::Error>> {
+        let (address, socket) = if v6 {
+            interface.v6.first()?;
+            (None, Self::get_v6_msocket(interface.index))
+        } else {
+            let address = interface.v4.first()?;
+            (Some(*address), Self::get_v4_msocket(address))
+        };
+        Some(socket.map(|socket| Link {
+            interface: Mutex::new(interface.clone()),
+            index: interface.index,
+            v6,
+            address,
+            socket: Arc::new(socket),
+            receiver: OnceLock::new(),
+        }))
+    }
 }
 
 impl Listener {
-    pub fn new(tracker: Tracker, responder: Responder) -> Result<Arc<Self>, String> {
-        let v4_socket = Self::get_v4_msocket().ok();
-        let v6_socket = Self::get_v6_msocket().ok();
-        // if v4 and v6 both fail, return an error
-        if v4_socket.is_none() && v6_socket.is_none() {
-            return Err("Failed to create both IPv4 and IPv6 sockets".to_string());
+    pub fn new(
+        tracker: Tracker,
+        responder: Responder,
+        config: &Config,
+    ) -> Result<Arc<Self>, Error> {
+        let interfaces = config.interfaces.select(interface::list());
+        if interfaces.is_empty() {
+            return Err(Error::NoInterfaces);
         }
+        let mut links = Vec::new();
+        let (mut v4_error, mut v6_error) = (None, None);
+        for interface in &interfaces {
+            for v6 in [false, true] {
+                match Self::open_link(interface, v6) {
+                    Some(Ok(link)) => links.push(Arc::new(link)),
+                    Some(Err(e)) if v6 => v6_error = Some(e),
+                    Some(Err(e)) => v4_error = Some(e),
+                    None => {}
+                }
+            }
+        }
+        // if v4 and v6 both fail on every interface, return an error
+        if links.is_empty() {
+            let missing = |family| std::io::Error::other(format!("no {} address", family));
+            return Err(Error::Bind {
+                v4: v4_error.unwrap_or_else(|| missing("IPv4")),
+                v6: v6_error.unwrap_or_else(|| missing("IPv6")),
+            });
+        }
+        let (work_giver, work_taker) = async_channel::bounded::<ChannelMessage>(config.work_queue);
         let listener = Arc::new(Listener {
-            v4_socket: Arc::new(v4_socket),
-            v6_socket: Arc::new(v6_socket),
+            links: RwLock::new(links),
             tracker,
             responder,
-            poison: OnceCell::new(),
-        });
-        // set the poison to the listener clone
-        if listener.poison.set(listener.clone()).is_err() {
-            return Err("Failed to set poison cell".to_string());
-        }
-        let listener_clone = Arc::clone(&listener);
-        tokio::spawn(async move {
-            let _ = listener_clone.listen().await;
+            workers: config.workers,
+            work_giver,
+            tasks: Mutex::new(Vec::new()),
+            passive: Mutex::new(None),
+            scheduler: Scheduler::default(),
+            asked: AskedQuestions::default(),
+            known_answers: KnownAnswers::default(),
         });
+        listener.listen(work_taker);
         Ok(listener)
     }
 
-    // Method to start listening for service discovery messages
-    pub async fn listen(&self) -> Result<(), String> {
-        let (work_giver, work_taker) = async_channel::bounded::<ChannelMessage>(50);
+    // a listener without any link for tests, whatever it sends goes nowhere
+    #[cfg(test)]
+    pub fn detached(tracker: Tracker) -> Arc<Self> {
+        let registry = super::register::Registry::new();
+        Arc::new(Listener {
+            links: RwLock::new(Vec::new()),
+            tracker,
+            responder: Responder::new(registry, 120),
+            workers: 0,
+            work_giver: async_channel::bounded(1).0,
+            tasks: Mutex::new(Vec::new()),
+            passive: Mutex::new(None),
+            scheduler: Scheduler::default(),
+            asked: AskedQuestions::default(),
+            known_answers: KnownAnswers::default(),
+        })
+    }
 
+    // interface and address family of every link
+    pub fn link_keys(&self) -> Vec<(u32, bool)> {
+        self.links
+            .read()
+            .unwrap()
+            .iter()
+            .map(|link| (link.index, link.v6))
+            .collect()
+    }
+
+    pub fn asked_questions(&self) -> &AskedQuestions {
+        &self.asked
+    }
+
+    fn links(&self, v6: bool) -> Vec<Arc<Link>> {
+        self.links
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|link| link.v6 == v6)
+            .cloned()
+            .collect()
+    }
+
+    // Method to start listening for service discovery messages on every link
+    fn listen(self: &Arc<Self>, work_taker: async_channel::Receiver<ChannelMessage>) {
         // Spawn a task to handle incoming messages
         self.handle_message(work_taker);
 
-        let mut v4_buf = [0u8; 1472];
-        let mut v6_buf = [0u8; 1472];
-        let mut v4_broken = false;
-        let mut v6_broken = false;
-        loop {
-            if v4_broken && v6_broken {
-                return Err("Both IPv4 and IPv6 sockets are broken".to_string());
-            }
-            tokio::select! {
-                result = async {
-                    match &*self.v4_socket {
-                        Some(socket) => socket.recv_from(&mut v4_buf).await,
-                        None => Err(std::io::Error::new(std::io::ErrorKind::Other, "IPv4 socket not initialized")),
-                    }
-                }, if !v4_broken => {
-                    match result {
-                        Ok((len, addr)) => {
-                            let _ = work_giver.send(ChannelMessage {
+        let links = self.links.read().unwrap().clone();
+        for link in links {
+            self.receive(link);
+        }
+    }
+
+    // spawns the task handing every packet of the link to the workers
+    fn receive(&self, link: Arc<Link>) {
+        let work_giver = self.work_giver.clone();
+        let receiver = link.clone();
+        let task = tokio::spawn(async move {
+            // RFC 6762 §17: messages carrying a large record may be up to 9000 bytes
+            let mut buf = [0u8; 9000];
+            loop {
+                match Self::recv_from_interface(&receiver, &mut buf).await {
+                    Ok((len, addr, interface)) => {
+                        let _ = work_giver
+                            .send(ChannelMessage {
                                 ip: addr,
-                                bytes: v4_buf[..len].to_vec(),
-                            }).await;
-                        }
-                        Err(_) => {
-                            v4_broken = true;
-                        }
+                                bytes: buf[..len].to_vec(),
+                                interface,
+                            })
+                            .await;
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Stopped listening on {}: {}",
+                            receiver.interface.lock().unwrap().name,
+                            Error::Receive(e)
+                        );
+                        break;
                     }
                 }
+            }
+        });
+        let _ = link.receiver.set(task.abort_handle());
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
 
-                result = async {
-                    match &*self.v6_socket {
-                        Some(socket) => socket.recv_from(&mut v6_buf).await,
-                        None => Err(std::io::Error::new(std::io::ErrorKind::Other, "IPv6 socket not initialized")),
-                    }
-                }, if !v6_broken => {
-                    match result {
-                        Ok((len, addr)) => {
-                            let _ = work_giver.send(ChannelMessage {
-                                ip: addr,
-                                bytes: v6_buf[..len].to_vec(),
-                            }).await;
-                        }
-                        Err(_) => {
-                            v6_broken = true;
-                        }
+    // Brings the links in line with the selected interfaces after a network change,
+    // joining the group on new interfaces and leaving it on the ones that went away.
+    pub fn sync_links(&self, interfaces: &[Interface]) {
+        let mut links = self.links.write().unwrap();
+        links.retain(|link| {
+            let current = interfaces.iter().find(|i| i.index == link.index);
+            let keep = current.is_some_and(|interface| match link.address {
+                Some(address) => interface.v4.contains(&address),
+                None => !interface.v6.is_empty(),
+            });
+            if let Some(current) = current.filter(|_| keep) {
+                *link.interface.lock().unwrap() = current.clone();
+            } else if let Some(receiver) = link.receiver.get() {
+                receiver.abort();
+            }
+            keep
+        });
+        for interface in interfaces {
+            for v6 in [false, true] {
+                if links
+                    .iter()
+                    .any(|l| l.index == interface.index && l.v6 == v6)
+                {
+                    continue;
+                }
+                match Self::open_link(interface, v6) {
+                    Some(Ok(link)) => {
+                        let link = Arc::new(link);
+                        self.receive(link.clone());
+                        links.push(link);
                     }
+                    Some(Err(e)) => eprintln!("Failed to listen on {}: {}", interface.name, e),
+                    None => {}
                 }
             }
         }
     }
 
-    async fn transfer_packet<'a>(
+    // Hands the records to a subscriber without waiting, a subscriber that doesn't keep up
+    // misses them rather than holding up the worker. Returns how many it missed.
+    fn transfer_packet<'a>(
         sender: &mpsc::Sender<Option<(Query, Response, u32)>>,
         packet: &Packet<'a>,
-    ) {
+    ) -> usize {
         println!("Transferring packet: {:?}", packet);
         let responses = [&packet.answers, &packet.additional_records]
             .into_iter()
             .flatten()
             .filter(|r| matches!(r.class, CLASS::IN))
-            .filter_map(|r| super::prepare_triplet_from_record(&r))
+            .filter_map(|r| super::prepare_triplet_from_record(r))
             .collect::<Vec<_>>();
+        let mut dropped = 0;
         for (query, response, ttl) in responses {
-            let _ = sender.send(Some((query, response, ttl))).await;
+            if let Err(TrySendError::Full(_)) = sender.try_send(Some((query, response, ttl))) {
+                dropped += 1;
+            }
         }
+        dropped
     }
 
-    async fn handle_response<'a>(packet: &Packet<'a>, tracker: Tracker) {
+    fn handle_response<'a>(packet: &Packet<'a>, tracker: Tracker, passive: Option<Feed>) {
         // Handle the response from the cache or the network
+        let mut feeds: Vec<Feed> = passive.into_iter().collect();
         for response in &packet.answers {
-            if matches!(response.class, CLASS::IN) {
-                if let Some((query, _, _)) = super::prepare_triplet_from_record(&response) {
-                    if let Some(sender) = tracker.get(&query) {
-                        Self::transfer_packet(sender.value(), &packet).await;
-                        break;
+            if matches!(response.class, CLASS::IN)
+                && let Some((query, _, _)) = super::prepare_triplet_from_record(response)
+                && let Some(tracked) = tracker.get(&query)
+            {
+                // every subscriber gets the packet once, even if it tracks several of its records
+                for feed in tracked.value() {
+                    if !feeds.iter().any(|f| f.same_channel(feed)) {
+                        feeds.push(feed.clone());
                     }
                 }
             }
         }
+        let dropped: usize = feeds
+            .iter()
+            .map(|feed| Self::transfer_packet(feed, packet))
+            .sum();
+        if dropped > 0 {
+            eprintln!(
+                "Dropped {} records of a response, a subscriber isn't keeping up",
+                dropped
+            );
+        }
+    }
+
+    // the interface a link runs on, without enumerating the interfaces of the host
+    fn link_interface(&self, index: u32) -> Option<Interface> {
+        let links = self.links.read().unwrap();
+        let link = links.iter().find(|link| link.index == index)?;
+        Some(link.interface.lock().unwrap().clone())
     }
 
     async fn handle_equery<'a>(
         ip: SocketAddr,
+        index: u32,
         packet: Packet<'a>,
         listener: Arc<Listener>,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
+        // RFC 6762 §6.2: only the addresses of the interface the query arrived on are answered with
+        let Some(interface) = listener.link_interface(index) else {
+            return Ok(());
+        };
+        // RFC 6762 §6.7: queries not sent from port 5353 come from plain resolvers, answer them directly
+        if ip.port() != 5353 {
+            let mut response_packet =
+                listener
+                    .responder
+                    .legacy_answer(packet.id(), packet.questions, &interface);
+            if response_packet.answers.is_empty() {
+                return Ok(());
+            }
+            if let Some(bytes) = super::serialize_packet(&mut response_packet) {
+                listener
+                    .send(ChannelMessage {
+                        ip,
+                        bytes,
+                        interface: index,
+                    })
+                    .await?;
+            }
+            return Ok(());
+        }
+        listener.asked.observe(index, ip.is_ipv6(), &packet);
         // Separate unicast and multicast questions
         let mut unicast_questions: Vec<Question<'a>> = vec![];
         let mut multicast_questions: Vec<Question<'a>> = vec![];
@@ -203,7 +505,9 @@ impl Listener {
         }
         // Prepare the response for unicast questions
         if !unicast_questions.is_empty() {
-            let mut response_packet = listener.responder.answer_queries(unicast_questions);
+            let mut response_packet = listener
+                .responder
+                .answer_queries(unicast_questions, &interface);
             if !(response_packet.answers.is_empty()
                 && response_packet.additional_records.is_empty())
             {
@@ -216,13 +520,21 @@ impl Listener {
                 // serialize the response packet
                 if let Some(bytes) = super::serialize_packet(&mut response_packet) {
                     // send the response back to  the outer world
-                    listener.send(ChannelMessage { ip, bytes }).await?;
+                    listener
+                        .send(ChannelMessage {
+                            ip,
+                            bytes,
+                            interface: index,
+                        })
+                        .await?;
                 }
             }
         }
         // Prepare the response for multicast questions
         if !multicast_questions.is_empty() {
-            let mut response_packet = listener.responder.answer_queries(multicast_questions);
+            let mut response_packet = listener
+                .responder
+                .answer_queries(multicast_questions, &interface);
             if !(response_packet.answers.is_empty()
                 && response_packet.additional_records.is_empty())
             {
@@ -230,29 +542,19 @@ impl Listener {
                 Responder::suppress_known_answers(&mut response_packet.answers, &packet.answers);
                 Responder::suppress_known_answers(
                     &mut response_packet.additional_records,
-                    &packet.additional_records,
+                    &packet.answers,
                 );
-                // serialize the response packet
-                if let Some(bytes) = super::serialize_packet(&mut response_packet) {
-                    match ip {
-                        SocketAddr::V4(_) => {
-                            listener
-                                .send(ChannelMessage {
-                                    ip: super::multicast_addr_v4().clone(),
-                                    bytes,
-                                })
-                                .await?;
-                        }
-                        SocketAddr::V6(_) => {
-                            listener
-                                .send(ChannelMessage {
-                                    ip: super::multicast_addr_v6().clone(),
-                                    bytes,
-                                })
-                                .await?;
-                        }
-                    }
-                }
+                // RFC 6762 §6: shared answers go out after a random delay, unique ones right away
+                listener
+                    .scheduler
+                    .respond(
+                        &listener,
+                        index,
+                        ip.is_ipv6(),
+                        response_packet,
+                        !packet.name_servers.is_empty(),
+                    )
+                    .await?;
             }
         }
 
@@ -260,42 +562,173 @@ impl Listener {
     }
 
     // Method to handle incoming service discovery messages
-    fn handle_message(&self, work_taker: async_channel::Receiver<ChannelMessage>) {
-        let total_cpus = num_cpus::get_physical();
-        for _ in 0..total_cpus {
+    fn handle_message(self: &Arc<Self>, work_taker: async_channel::Receiver<ChannelMessage>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        for _ in 0..self.workers {
             let work_taker_clone = work_taker.clone();
             let tracker_clone = self.tracker.clone();
-            let poison_clone: Arc<Listener> = self.poison.get().unwrap().clone();
-            tokio::spawn(async move {
+            let listener_clone = self.clone();
+            tasks.push(tokio::spawn(async move {
                 while let Ok(msg) = work_taker_clone.recv().await {
                     let tracker = tracker_clone.clone();
                     if let Ok(packet) = Packet::parse(&msg.bytes)
                         .map_err(|e| println!("Error parsing packet: {}", e))
                     {
+                        listener_clone.responder.detect_conflicts(&packet);
                         if packet.has_flags(PacketFlag::RESPONSE) {
-                            Self::handle_response(&packet, tracker).await;
+                            // RFC 6762 §6 and §18: responses come from port 5353 with a zero opcode and rcode
+                            if msg.ip.port() != 5353
+                                || packet.opcode() != OPCODE::StandardQuery
+                                || packet.rcode() != RCODE::NoError
+                            {
+                                continue;
+                            }
+                            // RFC 6762 §7.4: answers someone else just gave needn't be repeated
+                            listener_clone.scheduler.observe_response(
+                                msg.interface,
+                                msg.ip.is_ipv6(),
+                                &packet,
+                            );
+                            let passive = listener_clone.passive.lock().unwrap().clone();
+                            Self::handle_response(&packet, tracker, passive);
                         } else {
-                            _ = Self::handle_equery(msg.ip, packet, poison_clone.clone()).await;
+                            if let Some(generation) =
+                                listener_clone
+                                    .known_answers
+                                    .absorb(msg.ip, msg.interface, &packet)
+                            {
+                                listener_clone.answer_truncated(msg.ip, msg.interface, generation);
+                            } else {
+                                _ = Self::handle_equery(
+                                    msg.ip,
+                                    msg.interface,
+                                    packet,
+                                    listener_clone.clone(),
+                                )
+                                .await;
+                            }
                         };
                     }
                 }
+            }));
+        }
+    }
+
+    // RFC 6762 §7.2: answers a truncated query once its sender stopped sending known answers
+    fn answer_truncated(self: &Arc<Self>, ip: SocketAddr, interface: u32, generation: u64) {
+        let listener = self.clone();
+        tokio::spawn(async move {
+            sleep(known_answers::collect_delay()).await;
+            if let Some(packet) = listener.known_answers.take(ip, interface, generation) {
+                _ = Self::handle_equery(ip, interface, packet, listener.clone()).await;
+            }
+        });
+    }
+
+    // feeds every valid response on the link to the given channel, `None` turns it off again
+    pub fn set_passive_feed(&self, feed: Option<Feed>) {
+        *self.passive.lock().unwrap() = feed;
+    }
+
+    pub fn multicast_stats(&self) -> MulticastStats {
+        self.scheduler.stats()
+    }
+
+    // send a packet through the link of its interface, packets for the multicast group
+    // without an interface go out on every link of the family
+    pub async fn send(&self, msg: ChannelMessage) -> Result<(), Error> {
+        let mut links = self.links(msg.ip.is_ipv6());
+        let unicast = !msg.ip.ip().is_multicast();
+        if msg.interface != 0 && (!unicast || links.iter().any(|l| l.index == msg.interface)) {
+            links.retain(|link| link.index == msg.interface);
+        }
+        if unicast {
+            // the routing table picks the interface of a unicast destination anyway
+            links.truncate(1);
+        }
+        if links.is_empty() {
+            return Err(if msg.ip.is_ipv6() {
+                Error::NoIpv6
+            } else {
+                Error::NoIpv4
             });
         }
+        let mut result = Ok(());
+        let mut sent = false;
+        for link in links {
+            match link.socket.send_to(&msg.bytes, msg.ip).await {
+                Ok(_) => sent = true,
+                Err(e) => result = Err(Error::Send(e)),
+            }
+        }
+        if sent { Ok(()) } else { result }
     }
 
-    // send a packet
-    pub async fn send(&self, msg: ChannelMessage) -> Result<(), String> {
-        let socket = match msg.ip {
-            std::net::SocketAddr::V4(_) => self.v4_socket.clone(),
-            std::net::SocketAddr::V6(_) => self.v6_socket.clone(),
-        };
-        if let Some(socket) = socket.as_ref() {
-            if let Err(e) = socket.send_to(&msg.bytes, msg.ip).await {
-                return Err(format!("Failed to send message: {}", e));
+    // Multicasts a packet built for each interface, so its address records match the link it goes out on.
+    // A non-zero `interface` limits it to the links of that interface.
+    pub async fn multicast_each(
+        &self,
+        interface: u32,
+        build: impl Fn(&Interface) -> Option<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let mut links = self.links.read().unwrap().clone();
+        if interface != 0 {
+            links.retain(|link| link.index == interface);
+        }
+        let mut packets: HashMap<u32, Option<Vec<u8>>> = HashMap::new();
+        let mut result = Err(Error::Encode("packet"));
+        for link in links {
+            let bytes = packets
+                .entry(link.index)
+                .or_insert_with(|| build(&link.interface.lock().unwrap()));
+            let Some(bytes) = bytes else {
+                continue;
+            };
+            let group = if link.v6 {
+                super::multicast_addr_v6()
+            } else {
+                super::multicast_addr_v4()
+            };
+            match link.socket.send_to(bytes, group).await {
+                Ok(_) => result = Ok(()),
+                Err(e) if result.is_err() => result = Err(Error::Send(e)),
+                Err(_) => {}
             }
-        } else {
-            return Err("Socket not initialized".to_string());
         }
-        Ok(())
+        result
+    }
+
+    // send a packet to the multicast group of every family, failing only if none went out
+    pub async fn multicast(&self, bytes: Vec<u8>) -> Result<(), Error> {
+        self.multicast_on(0, bytes).await
+    }
+
+    // the same on the links of a single interface, 0 for every interface
+    pub async fn multicast_on(&self, interface: u32, bytes: Vec<u8>) -> Result<(), Error> {
+        let v4 = self
+            .send(ChannelMessage {
+                ip: *super::multicast_addr_v4(),
+                bytes: bytes.clone(),
+                interface,
+            })
+            .await;
+        let v6 = self
+            .send(ChannelMessage {
+                ip: *super::multicast_addr_v6(),
+                bytes,
+                interface,
+            })
+            .await;
+        v4.or(v6)
+    }
+
+    // stops receiving and closes the sockets, nothing can be sent afterwards
+    pub async fn shutdown(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+        self.links.write().unwrap().clear();
     }
 }
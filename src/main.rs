@@ -1,22 +1,26 @@
 use home_web::*;
 use std::{
-    collections::HashMap,
     io::{self, BufRead},
     time::Duration,
 };
 
 #[tokio::main]
 async fn main() {
-    let mut hw = HomeWeb::new().expect("Failed to create HomeWeb instance");
-    let mut metadata = HashMap::new();
+    let hw = HomeWeb::new().expect("Failed to create HomeWeb instance");
+    let mut metadata = TxtRecord::new();
     let ins_name = format!("{}._homecast._tcp.local", random_alphanumeric_string(6));
 
-    metadata.insert("magic".to_string(), random_alphanumeric_string(5));
-    _ = hw.register_device(
-        Instance::new(ins_name.clone(), 8080, metadata).expect("Failed to create instance"),
-    );
+    metadata
+        .insert("magic", random_alphanumeric_string(5))
+        .expect("Failed to set metadata");
+    let registration = hw
+        .register_device(
+            Instance::new(ins_name, 8080, metadata).expect("Failed to create instance"),
+        )
+        .await
+        .expect("Failed to register instance");
 
-    println!("HomeWeb is running... @ {}", ins_name);
+    println!("HomeWeb is running... @ {}", registration.name());
     println!(
         "Press Enter to resolve '_homecast._tcp.local' or type a custom name for resolving instance:"
     );
@@ -26,16 +30,25 @@ async fn main() {
         if input.trim().is_empty() {
             // Resolve the service only such as homecast
             let devices = hw
-                .get_devices("_homecast._tcp.local".to_string(), Duration::from_secs(3))
+                .get_devices(
+                    "_homecast._tcp.local".to_string(),
+                    Duration::from_secs(3),
+                    QueryMode::UnicastFirst,
+                )
                 .await;
             println!("Discovered devices: {:?}", devices);
         } else {
             // Resolve the custom name
             let device = hw
-                .resolve_device(input.trim().to_string(), Duration::from_secs(3))
+                .resolve_device(
+                    input.trim().to_string(),
+                    Duration::from_secs(3),
+                    QueryMode::UnicastFirst,
+                )
                 .await;
             println!("Resolved device: {:?}", device);
         }
     }
     println!("Shutting down HomeWeb...");
+    hw.shutdown().await;
 }
@@ -0,0 +1,380 @@
+use super::error::Error;
+use super::interface::Interface;
+use super::listener::Listener;
+use super::register::Registry;
+use super::responder::Responder;
+use super::types::{Instance, ProbeSignal, Registration};
+use rand::{Rng, rng};
+use simple_dns::{CLASS, Name, Packet, QCLASS, QTYPE, Question, ResourceRecord};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, WeakSender};
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout};
+
+// RFC 6762 §8.1: three probes, 250 ms apart
+const PROBE_COUNT: usize = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+// RFC 6762 §8.2: the loser of a tie-break waits a second before probing again
+const LOST_BACKOFF: Duration = Duration::from_secs(1);
+// RFC 6762 §8.1: rate limit probing once a name keeps conflicting
+const MAX_CONFLICTS: usize = 15;
+const CONFLICT_WINDOW: Duration = Duration::from_secs(10);
+const CONFLICT_BACKOFF: Duration = Duration::from_secs(5);
+
+// (class, type, rdata) is the order records are compared in during a tie-break
+fn record_key(record: &ResourceRecord) -> (u16, u16, Vec<u8>) {
+    (
+        record.class as u16,
+        u16::from(record.rdata.type_code()),
+        super::rdata_bytes(record),
+    )
+}
+
+// two records are the same if they only differ in name case, ttl or the cache-flush bit
+pub fn same_record(a: &ResourceRecord, b: &ResourceRecord) -> bool {
+    record_key(a) == record_key(b)
+}
+
+// Lexicographically compares our proposed records against theirs, `Less` means we lost.
+pub fn tie_break(ours: &[ResourceRecord], theirs: &[ResourceRecord]) -> Ordering {
+    let mut ours: Vec<_> = ours.iter().map(record_key).collect();
+    let mut theirs: Vec<_> = theirs.iter().map(record_key).collect();
+    ours.sort();
+    theirs.sort();
+    // a host with more records wins when all shared records are identical
+    ours.cmp(&theirs)
+}
+
+#[derive(Clone)]
+pub struct Prober {
+    registry: Registry,
+    responder: Responder,
+    listener: Arc<Listener>,
+}
+
+impl Prober {
+    pub fn new(registry: Registry, responder: Responder, listener: Arc<Listener>) -> Self {
+        Prober {
+            registry,
+            responder,
+            listener,
+        }
+    }
+
+    fn prepare_probe(
+        qname: Name<'static>,
+        records: Vec<ResourceRecord<'static>>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut packet = Packet::new_query(0);
+        // ask for any record of the name, preferring unicast replies
+        packet.questions.push(Question::new(
+            qname,
+            QTYPE::ANY,
+            QCLASS::CLASS(CLASS::IN),
+            true,
+        ));
+        // the records we are about to claim go into the authority section for tie-breaking
+        packet.name_servers = records;
+        super::serialize_packet(&mut packet).ok_or(Error::Encode("probe packet"))
+    }
+
+    // probes the name of the instance on the interface, 0 for every interface
+    async fn probe(
+        &self,
+        instance: &Instance,
+        interface: &mut u32,
+        signals: &mut mpsc::Receiver<ProbeSignal>,
+    ) -> Result<bool, Error> {
+        let bytes = Self::prepare_probe(instance.qname(), self.responder.unique_records(instance))?;
+        self.run(bytes, interface, signals).await
+    }
+
+    // Probes the host name with the addresses of the interface, e.g. after it got a new one.
+    // Ok(false) means another host answers for the name on that link.
+    pub async fn probe_host(&self, interface: &Interface) -> Result<bool, Error> {
+        let bytes = Self::prepare_probe(
+            Name::new_unchecked(super::mdns_hostname()).into_owned(),
+            self.responder.host_records(interface),
+        )?;
+        let (signal, mut signals) = mpsc::channel(4);
+        self.registry.probe_host(interface.clone(), signal);
+        let mut index = interface.index;
+        let probed = self.run(bytes, &mut index, &mut signals).await;
+        self.registry.host_probed(interface.index);
+        probed
+    }
+
+    // Sends the probe for a single name, Ok(false) means somebody else already owns it.
+    // `interface` widens to every interface if another one changes while probing.
+    async fn run(
+        &self,
+        bytes: Vec<u8>,
+        interface: &mut u32,
+        signals: &mut mpsc::Receiver<ProbeSignal>,
+    ) -> Result<bool, Error> {
+        // spread out hosts which boot at the same moment
+        let delay = Duration::from_millis(rng().random_range(0..250));
+        sleep(delay).await;
+        'probing: loop {
+            for _ in 0..PROBE_COUNT {
+                self.listener
+                    .multicast_on(*interface, bytes.clone())
+                    .await?;
+                match timeout(PROBE_INTERVAL, signals.recv()).await {
+                    Ok(Some(ProbeSignal::Conflict)) => return Ok(false),
+                    Ok(Some(ProbeSignal::Lost)) => {
+                        sleep(LOST_BACKOFF).await;
+                        // drop whatever piled up while backing off and start over, keeping
+                        // track of the interfaces that changed meanwhile
+                        while let Ok(signal) = signals.try_recv() {
+                            if let ProbeSignal::Reprobe(changed) = signal
+                                && changed != *interface
+                            {
+                                *interface = 0;
+                            }
+                        }
+                        continue 'probing;
+                    }
+                    Ok(Some(ProbeSignal::Reprobe(changed))) => {
+                        if changed != *interface {
+                            *interface = 0;
+                        }
+                        continue 'probing;
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(true);
+        }
+    }
+
+    // Probes the instance, renaming it on every conflict, and returns the name it ended up owning.
+    async fn claim(
+        &self,
+        mut instance: Instance,
+        registration: u64,
+        signal: &mpsc::Sender<ProbeSignal>,
+        current: &watch::Sender<Instance>,
+        signals: &mut mpsc::Receiver<ProbeSignal>,
+    ) -> Result<Instance, Error> {
+        let mut conflicts: VecDeque<Instant> = VecDeque::new();
+        loop {
+            // names taken by another local registration are renamed without probing
+            if !self
+                .registry
+                .claim(&instance, registration, signal.clone(), current.clone())?
+            {
+                instance = instance.renamed();
+                continue;
+            }
+            while signals.try_recv().is_ok() {}
+            match self.probe(&instance, &mut 0, signals).await {
+                Ok(true) => {
+                    return self
+                        .registry
+                        .confirm(&instance)
+                        .ok_or_else(|| Error::NotRegistered(instance.name().to_string()));
+                }
+                Ok(false) => {
+                    eprintln!("Name conflict for {}, renaming", instance.name());
+                    let latest = self.registry.release(&instance);
+                    instance = latest.unwrap_or(instance).renamed();
+                    // RFC 6762 §8.1: after 15 conflicts within 10 seconds slow down to one probe every 5 seconds
+                    let now = Instant::now();
+                    conflicts.push_back(now);
+                    while conflicts
+                        .front()
+                        .is_some_and(|at| now.duration_since(*at) > CONFLICT_WINDOW)
+                    {
+                        conflicts.pop_front();
+                    }
+                    if conflicts.len() >= MAX_CONFLICTS {
+                        sleep(CONFLICT_BACKOFF).await;
+                    }
+                }
+                Err(e) => {
+                    self.registry.release(&instance);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Keeps defending the name after registration, re-probing and renaming once someone else claims it.
+    fn guard(
+        &self,
+        registration: u64,
+        signal: WeakSender<ProbeSignal>,
+        mut signals: mpsc::Receiver<ProbeSignal>,
+        current: watch::Sender<Instance>,
+    ) {
+        let prober = self.clone();
+        tokio::spawn(async move {
+            // the registry holds the only strong sender, unregistering closes the channel
+            while let Some(received) = signals.recv().await {
+                // a network change only affects the interface it happened on
+                let mut interface = match received {
+                    ProbeSignal::Lost => continue,
+                    ProbeSignal::Conflict => 0,
+                    ProbeSignal::Reprobe(interface) => interface,
+                };
+                let Some(signal) = signal.upgrade() else {
+                    break;
+                };
+                // RFC 6762 §9, §13: a conflicting response or a network change puts the records
+                // back into probing, the claim stays in place so we keep answering meanwhile
+                let Some(claim) = prober.registry.claimed(registration) else {
+                    break;
+                };
+                match prober
+                    .probe(&claim.instance, &mut interface, &mut signals)
+                    .await
+                {
+                    Ok(true) => {
+                        prober.registry.reannounce(registration, interface);
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        eprintln!("Failed to probe {}: {}", claim.instance.name(), e);
+                        continue;
+                    }
+                }
+                eprintln!("Name conflict for {}, renaming", claim.instance.name());
+                let latest = prober.registry.release(&claim.instance);
+                // confirming the claim hands the new name over to the registration
+                if let Err(e) = prober
+                    .claim(
+                        latest.unwrap_or(claim.instance).renamed(),
+                        registration,
+                        &signal,
+                        &current,
+                        &mut signals,
+                    )
+                    .await
+                {
+                    eprintln!("Failed to reclaim instance: {}", e);
+                    break;
+                }
+            }
+        });
+    }
+
+    // Probes the name of the instance and registers it once nobody else on the link claims it.
+    pub async fn register(&self, instance: Instance) -> Result<Registration, Error> {
+        self.responder.check_size(&instance)?;
+        let registration = self.registry.open();
+        let (signal, mut signals) = mpsc::channel(4);
+        let (current, receiver) = watch::channel(instance.clone());
+        if let Err(e) = self
+            .claim(instance, registration, &signal, &current, &mut signals)
+            .await
+        {
+            self.registry.close(registration);
+            return Err(e);
+        }
+        self.guard(registration, signal.downgrade(), signals, current);
+        Ok(Registration::new(registration, receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txt::TxtRecord;
+    use simple_dns::rdata::{A, RData};
+
+    fn a_record(address: [u8; 4]) -> ResourceRecord<'static> {
+        ResourceRecord::new(
+            Name::new_unchecked("host.local"),
+            CLASS::IN,
+            120,
+            RData::A(A {
+                address: u32::from_be_bytes(address),
+            }),
+        )
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let low = [a_record([169, 254, 99, 200])];
+        let high = [a_record([169, 254, 200, 50])];
+        assert_eq!(tie_break(&low, &high), Ordering::Less);
+        assert_eq!(tie_break(&high, &low), Ordering::Greater);
+        assert_eq!(tie_break(&low, &low), Ordering::Equal);
+        // the host with more records wins if everything else is equal
+        let more = [a_record([169, 254, 99, 200]), a_record([169, 254, 200, 50])];
+        assert_eq!(tie_break(&low, &more), Ordering::Less);
+    }
+
+    // a record taking up `size` bytes on the wire
+    fn metadata_of_size(size: usize) -> TxtRecord {
+        let mut metadata = TxtRecord::new();
+        let mut left = size;
+        for i in 0.. {
+            if left == 0 {
+                break;
+            }
+            // "kNN=" and the length byte come on top of the value
+            let value = (left - 5).min(240);
+            metadata
+                .insert(&format!("k{:02}", i), vec![b'x'; value])
+                .unwrap();
+            left -= value + 5;
+        }
+        metadata
+    }
+
+    #[test]
+    fn test_large_txt_record() {
+        // a record too large for the MTU is probed and announced in a larger message
+        let instance = Instance::new(
+            "tv._homecast._tcp.local".to_string(),
+            8080,
+            metadata_of_size(8800),
+        )
+        .unwrap();
+        let registry = Registry::new();
+        registry.register_device(instance.clone());
+        let responder = Responder::new(registry, 120);
+        assert!(responder.check_size(&instance).is_ok());
+
+        let records = responder.unique_records(&instance);
+        let bytes = Prober::prepare_probe(instance.qname(), records).unwrap();
+        assert!(bytes.len() > super::super::MAX_PACKET_SIZE);
+        assert!(bytes.len() <= super::super::MAX_MESSAGE_SIZE);
+
+        let interface = Interface {
+            name: "eth0".to_string(),
+            index: 2,
+            v4: vec![[192, 168, 1, 2].into()],
+            v6: vec![],
+        };
+        let mut packet = responder.announcement(&instance, &interface);
+        let count = packet.answers.len();
+        let bytes = super::super::serialize_packet(&mut packet).unwrap();
+        let parsed = Packet::parse(&bytes).unwrap();
+        assert_eq!(parsed.answers.len(), count);
+        assert!(
+            parsed
+                .answers
+                .iter()
+                .any(|record| matches!(record.rdata, RData::TXT(_)))
+        );
+
+        // the RFC 6763 limit holds for the record, the names of the instance have to fit as well
+        let long = Instance::new(
+            format!("{}._homecast._tcp.local", "x".repeat(63)),
+            8080,
+            metadata_of_size(8900),
+        )
+        .unwrap();
+        assert!(matches!(
+            responder.check_size(&long),
+            Err(Error::InvalidTxtRecord(_))
+        ));
+    }
+}
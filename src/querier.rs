@@ -1,10 +1,13 @@
 use super::cache::*;
+use super::error::Error;
 use super::listener::Listener;
 use super::types::*;
-use simple_dns::{CLASS, Packet, QCLASS, Question, ResourceRecord};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use dashmap::{DashMap, Entry};
+use simple_dns::{CLASS, Packet, PacketFlag, QCLASS, QTYPE, Question, ResourceRecord};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
 struct TimeBomb(
@@ -14,7 +17,7 @@ struct TimeBomb(
 
 impl TimeBomb {
     pub fn new(duration: Duration) -> Self {
-        let (trigger, receiver) = mpsc::channel(1);
+        let (trigger, receiver) = mpsc::channel(FEED_CAPACITY);
         let trigger_clone = trigger.clone();
         tokio::spawn(async move {
             sleep(duration).await;
@@ -24,9 +27,272 @@ impl TimeBomb {
     }
 }
 
+// RFC 6762 §10.1, §10.2: flushed and goodbye records linger for a second before they are dropped
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+// keeps the record around for one more second only
+async fn expire_soon(cache: &Cache, query: &Query, response: &Response) {
+    let mut response = response.clone();
+    response.ends_at = SystemTime::now() + FLUSH_DELAY;
+    cache.remove(query.clone(), response.clone()).await;
+    cache
+        .insert(query.clone(), response, FLUSH_DELAY.as_secs() as u32)
+        .await;
+}
+
+// Puts a received record into the cache, honouring the cache-flush bit and goodbyes.
+async fn store(cache: &Cache, query: Query, response: Response, ttl: u32) {
+    let now = SystemTime::now();
+    if response.cache_flush {
+        // records of the same name and type that didn't arrive along with this one are stale
+        for cached in cache.get(&query).await {
+            let older = now
+                .duration_since(cached.received_at)
+                .is_ok_and(|age| age > FLUSH_DELAY);
+            if *cached != response && older && cached.ends_at > now + FLUSH_DELAY {
+                expire_soon(cache, &query, &cached).await;
+            }
+        }
+    }
+    if ttl == 0 {
+        if cache
+            .get(&query)
+            .await
+            .iter()
+            .any(|cached| **cached == response)
+        {
+            expire_soon(cache, &query, &response).await;
+        }
+        return;
+    }
+    // the cache keeps the value it already holds, replace it so the new expiry sticks
+    cache.remove(query.clone(), response.clone()).await;
+    cache.insert(query, response, ttl).await;
+}
+
+// RFC 6762 §7.3: a query another host sent this recently stands in for our own
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(1);
+
+// a multicast question another host asked on one of our links
+#[derive(Debug)]
+struct Asked {
+    name: String,
+    qtype: QTYPE,
+    interface: u32,
+    v6: bool,
+    at: Instant,
+    known: Vec<Response>,
+}
+
+// Questions other hosts multicast on the link along with their known answers, so we can skip
+// sending the same question while their answers are on the way anyway.
+#[derive(Debug, Default)]
+pub struct AskedQuestions {
+    asked: Mutex<Vec<Asked>>,
+}
+
+impl AskedQuestions {
+    pub fn observe(&self, interface: u32, v6: bool, packet: &Packet) {
+        let now = Instant::now();
+        let mut asked = self.asked.lock().unwrap();
+        asked.retain(|a| now.duration_since(a.at) < DUPLICATE_WINDOW);
+        // answers to QU questions are unicast to the asker, we'd never see them
+        for question in packet.questions.iter().filter(|q| !q.unicast_response) {
+            let name = question.qname.to_string().to_lowercase();
+            let known = packet
+                .answers
+                .iter()
+                .filter(|r| r.name.to_string().eq_ignore_ascii_case(&name))
+                .filter_map(super::prepare_triplet_from_record)
+                .filter(|(q, _, _)| QTYPE::from(q.qtype.clone()) == question.qtype)
+                .map(|(_, response, _)| response)
+                .collect();
+            asked.push(Asked {
+                name,
+                qtype: question.qtype,
+                interface,
+                v6,
+                at: now,
+                known,
+            });
+        }
+    }
+
+    // Whether the question was just asked on every one of the links, each time listing
+    // only known answers we know as well, so no response gets suppressed that we lack.
+    pub fn covers(&self, query: &Query, known: &[Arc<Response>], links: &[(u32, bool)]) -> bool {
+        let name = query.qname.to_string().to_lowercase();
+        let qtype = QTYPE::from(query.qtype.clone());
+        let asked = self.asked.lock().unwrap();
+        !links.is_empty()
+            && links.iter().all(|&(interface, v6)| {
+                asked.iter().any(|a| {
+                    a.interface == interface
+                        && a.v6 == v6
+                        && a.qtype == qtype
+                        && a.name == name
+                        && a.at.elapsed() < DUPLICATE_WINDOW
+                        && a.known
+                            .iter()
+                            .all(|k| known.iter().any(|ours| **ours == *k))
+                })
+            })
+    }
+}
+
+// RFC 6762 §7.1: the cached answers with more than half of the TTL they came with left, along
+// with that remaining TTL. Answers closer to expiry are left for responders to refresh.
+fn known_answers(cached: Vec<Arc<Response>>, now: SystemTime) -> Vec<(Arc<Response>, u32)> {
+    cached
+        .into_iter()
+        .filter_map(|response| {
+            let lifetime = response
+                .ends_at
+                .duration_since(response.received_at)
+                .unwrap_or_default();
+            let remaining = response.ends_at.duration_since(now).unwrap_or_default();
+            let ttl = remaining.as_secs() as u32;
+            (remaining * 2 > lifetime).then_some((response, ttl))
+        })
+        .collect()
+}
+
+// The query and the answers we already know, known answers which don't fit into the first
+// packet follow in further ones, all but the last with the TC bit set (RFC 6762 §7.2).
+fn prepare_query(
+    query: &Query,
+    unicast: bool,
+    known: &[(Arc<Response>, u32)],
+) -> Option<Vec<Vec<u8>>> {
+    // make a query packet, `unicast` sets the QU bit
+    let mut packet = Packet::new_query(0);
+    packet.questions.push(Question::new(
+        query.qname.clone(),
+        query.qtype.clone().into(),
+        QCLASS::CLASS(CLASS::IN),
+        unicast,
+    ));
+    let mut size = packet.build_bytes_vec().ok()?.len();
+    let mut packets = vec![packet];
+
+    // add previous known answers to the packet for answer supression
+    for (response, remaining_ttl) in known {
+        let record = ResourceRecord::new(
+            query.qname.clone(),
+            CLASS::IN,
+            *remaining_ttl,
+            response.inner.clone().into(),
+        );
+        // a single known answer too large for a packet of its own is left out
+        let Some(record_size) = super::record_size(&record)
+            .filter(|record_size| super::HEADER_SIZE + record_size <= super::MAX_PACKET_SIZE)
+        else {
+            continue;
+        };
+        if size + record_size > super::MAX_PACKET_SIZE {
+            packets.push(Packet::new_query(0));
+            size = super::HEADER_SIZE;
+        }
+        packets.last_mut()?.answers.push(record);
+        size += record_size;
+    }
+    let count = packets.len();
+    packets
+        .iter_mut()
+        .enumerate()
+        .map(|(i, packet)| {
+            if i + 1 < count {
+                packet.set_flags(PacketFlag::TRUNCATION);
+            }
+            packet.build_bytes_vec().ok()
+        })
+        .collect()
+}
+
+// RFC 6762 §7.3: someone else just asked the same, their answers reach us as well
+fn asked_by_others(query: &Query, known: &[(Arc<Response>, u32)], listener: &Listener) -> bool {
+    let known: Vec<_> = known.iter().map(|(response, _)| response.clone()).collect();
+    listener
+        .asked_questions()
+        .covers(query, &known, &listener.link_keys())
+}
+
+// sends the packets of a query in order, the continuations right after the question
+async fn multicast_all(listener: &Listener, packets: Vec<Vec<u8>>) -> Result<(), Error> {
+    for bytes in packets {
+        listener.multicast(bytes).await?;
+    }
+    Ok(())
+}
+
+// A lookup counted as waiting on its query until it is done or dropped.
+struct Asking<'a> {
+    asking: &'a DashMap<Query, usize>,
+    query: &'a Query,
+    first: bool,
+}
+
+impl<'a> Asking<'a> {
+    fn start(asking: &'a DashMap<Query, usize>, query: &'a Query) -> Self {
+        let mut count = asking.entry(query.clone()).or_insert(0);
+        *count += 1;
+        let first = *count == 1;
+        drop(count);
+        Asking {
+            asking,
+            query,
+            first,
+        }
+    }
+}
+
+impl Drop for Asking<'_> {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut entry) = self.asking.entry(self.query.clone()) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+// The queries a lookup's feed is tracked for, untracked again once it is done or dropped.
+pub struct Tracking<'a> {
+    querier: &'a Querier,
+    feed: Feed,
+    queries: Vec<Query>,
+}
+
+impl<'a> Tracking<'a> {
+    pub fn new(querier: &'a Querier, feed: Feed) -> Self {
+        Tracking {
+            querier,
+            feed,
+            queries: Vec::new(),
+        }
+    }
+
+    pub fn track(&mut self, query: &Query) {
+        self.querier.track(query, self.feed.clone());
+        self.queries.push(query.clone());
+    }
+}
+
+impl Drop for Tracking<'_> {
+    fn drop(&mut self) {
+        for query in &self.queries {
+            self.querier.untrack(query, &self.feed);
+        }
+    }
+}
+
 pub struct Querier {
     cache: Cache,
     tracker: Tracker,
+    // lookups waiting for answers per query, a question already on the wire isn't sent again
+    asking: DashMap<Query, usize>,
+    refresher: OnceLock<JoinHandle<()>>,
 }
 
 impl Querier {
@@ -43,19 +309,36 @@ impl Querier {
 }
 
 impl Querier {
-    pub fn new(cache: Cache, tracker: Tracker, listener: Arc<Listener>) -> Arc<Self> {
-        let querier = Arc::new(Querier { cache, tracker });
+    pub fn new(
+        cache: Cache,
+        tracker: Tracker,
+        listener: Arc<Listener>,
+        refresh_interval: Duration,
+    ) -> Arc<Self> {
+        let querier = Arc::new(Querier {
+            cache,
+            tracker,
+            asking: DashMap::new(),
+            refresher: OnceLock::new(),
+        });
         let querier_clone = querier.clone();
-        tokio::spawn(async move {
-            //refesh every 60 seconds
+        let refresher = tokio::spawn(async move {
             loop {
-                sleep(Duration::from_secs(60)).await;
+                sleep(refresh_interval).await;
                 querier_clone.refresh_cache(&listener).await;
             }
         });
+        let _ = querier.refresher.set(refresher);
         querier
     }
 
+    // stops the background cache refresh
+    pub fn stop(&self) {
+        if let Some(refresher) = self.refresher.get() {
+            refresher.abort();
+        }
+    }
+
     async fn refresh_cache(&self, listener: &Listener) {
         // iterate the entire cache and if the end time is about to expire lets say 10 seconds left only then will execute the query
         println!("Refreshing cache...");
@@ -75,41 +358,84 @@ impl Querier {
 
         for query in queries_to_refresh {
             let _ = self
-                .query(query, Duration::from_secs(5), true, listener)
+                .query(
+                    query,
+                    Duration::from_secs(5),
+                    true,
+                    QueryMode::Multicast,
+                    listener,
+                )
                 .await;
         }
     }
 
-    async fn prepare_query(&self, query: &Query) -> Option<Vec<u8>> {
-        // make a query packet
-        let mut packet = Packet::new_query(0);
-        packet.questions.push(Question::new(
-            query.qname.clone(),
-            query.qtype.clone().into(),
-            QCLASS::CLASS(CLASS::IN),
-            false,
-        ));
+    // multicasts the query along with the answers we already know
+    pub async fn send_query(
+        &self,
+        query: &Query,
+        unicast: bool,
+        listener: &Listener,
+    ) -> Result<(), Error> {
+        let known = known_answers(self.cache.get(query).await, SystemTime::now());
+        // answers to someone else's question are multicast, those to a QU one we'd miss
+        if !unicast && asked_by_others(query, &known, listener) {
+            return Ok(());
+        }
+        let packets =
+            prepare_query(query, unicast, &known).ok_or(Error::Encode("query message"))?;
+        multicast_all(listener, packets).await
+    }
+
+    // multicasts the query right away, for lookups someone is waiting on
+    pub async fn ask(
+        &self,
+        query: &Query,
+        unicast: bool,
+        listener: &Listener,
+    ) -> Result<(), Error> {
+        let known = known_answers(self.cache.get(query).await, SystemTime::now());
+        let packets =
+            prepare_query(query, unicast, &known).ok_or(Error::Encode("query message"))?;
+        multicast_all(listener, packets).await
+    }
+
+    // subscribes the feed to every response carrying records for the query
+    pub fn track(&self, query: &Query, feed: Feed) {
+        self.tracker.entry(query.clone()).or_default().push(feed);
+    }
 
-        // add previous known answers to the packet for answer supression
-        let responses = self.cache.get(query).await;
-        if !responses.is_empty() {
-            for response in responses {
-                let remaining_ttl = response
-                    .ends_at
-                    .duration_since(SystemTime::now())
-                    .unwrap_or(Duration::from_secs(0))
-                    .as_secs() as u32;
-                let record = ResourceRecord::new(
-                    query.qname.clone(),
-                    CLASS::IN,
-                    remaining_ttl,
-                    response.inner.clone().into(),
-                );
-                packet.answers.push(record);
+    pub fn untrack(&self, query: &Query, feed: &Feed) {
+        if let Entry::Occupied(mut entry) = self.tracker.entry(query.clone()) {
+            entry.get_mut().retain(|f| !f.same_channel(feed));
+            if entry.get().is_empty() {
+                entry.remove();
             }
         }
-        // Serialize the packet to bytes
-        return super::serialize_packet(&mut packet);
+    }
+
+    // Folds every response seen on the link into the cache, not only the ones we asked for.
+    pub fn set_passive(&self, listener: &Listener, enabled: bool) {
+        if !enabled {
+            // the listener holds the only sender, dropping it ends the task below
+            listener.set_passive_feed(None);
+            return;
+        }
+        let (feed, mut records) = mpsc::channel(FEED_CAPACITY);
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            while let Some(Some((query, response, ttl))) = records.recv().await {
+                store(&cache, query, response, ttl).await;
+            }
+        });
+        listener.set_passive_feed(Some(feed));
+    }
+
+    pub async fn cached(&self, query: &Query) -> Vec<Arc<Response>> {
+        self.cache.get(query).await
+    }
+
+    pub async fn remember(&self, query: Query, response: Response, ttl: u32) {
+        store(&self.cache, query, response, ttl).await;
     }
 
     pub async fn query(
@@ -117,56 +443,44 @@ impl Querier {
         query: Query,
         duration: Duration,
         bypass_cache: bool,
+        mode: QueryMode,
         listener: &Listener,
     ) -> Vec<Arc<Response>> {
         let response = self.cache.get(&query).await;
-        if bypass_cache || (response.is_empty() && !self.tracker.contains_key(&query)) {
-            // If the response is not cached and not being tracked, we need to send a query
-            let query_message = self.prepare_query(&query).await;
+        if bypass_cache || response.is_empty() {
+            // If the response is not cached and no other lookup asked, we need to send a query
+            // a lookup sends a single query, the first one
+            let unicast = mode.unicast(true);
+            let known = known_answers(response, SystemTime::now());
+            let Some(query_packets) = prepare_query(&query, unicast, &known) else {
+                eprintln!("Failed to prepare query message.");
+                return vec![];
+            };
             let TimeBomb(trigger, mut receiver) = TimeBomb::new(duration);
-            self.tracker.insert(query.clone(), trigger);
-            let query_message = match query_message {
-                Some(msg) => msg,
-                None => {
-                    eprintln!("Failed to prepare query message.");
-                    self.tracker.remove(&query);
-                    return vec![];
-                }
+            let mut tracking = Tracking::new(self, trigger);
+            tracking.track(&query);
+            // another lookup already sent this question, wait for the same answers
+            let asking = Asking::start(&self.asking, &query);
+            // trigger a network query, a refresh someone else just asked for comes in anyway
+            let send = if bypass_cache {
+                unicast || !asked_by_others(&query, &known, listener)
+            } else {
+                asking.first
             };
-            // trigger a network query
-            if let Err(e) = listener
-                .send(ChannelMessage {
-                    ip: *super::multicast_addr_v4(),
-                    bytes: query_message.clone(),
-                })
-                .await
-            {
-                eprintln!("Failed to send query in ip4 socket: {}", e);
-                // return vec![];
-            }
-
-            if let Err(e) = listener
-                .send(ChannelMessage {
-                    ip: *super::multicast_addr_v6(),
-                    bytes: query_message,
-                })
-                .await
-            {
-                eprintln!("Failed to send query in ip6 socket: {}", e);
-                // return vec![];
+            if send && let Err(e) = multicast_all(listener, query_packets).await {
+                eprintln!("Failed to send query: {}", e);
             }
 
             // Wait for the time bomb to trigger or for a response to be cached
             while let Some(response) = receiver.recv().await {
                 if let Some((qry, response, ttl)) = response {
-                    self.cache.insert(qry, response, ttl).await;
+                    store(&self.cache, qry, response, ttl).await;
                 } else {
                     let cache_resp = self.cache.get(&query).await;
                     println!(
                         "Querier received timeout, for {:?} with {:?}",
                         query, cache_resp
                     );
-                    self.tracker.remove(&query);
                     return cache_resp;
                 }
             }
@@ -176,3 +490,173 @@ impl Querier {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazuka::{SkmvCache, SkmvConfig};
+    use simple_dns::Name;
+    use std::net::Ipv4Addr;
+
+    fn a_response(address: Ipv4Addr, cache_flush: bool) -> Response {
+        let now = SystemTime::now();
+        Response {
+            inner: ResponseInner::A { address },
+            ends_at: now + Duration::from_secs(120),
+            received_at: now,
+            cache_flush,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_cache_flush_and_goodbye() {
+        let cache: Cache = Arc::new(SkmvCache::new(SkmvConfig {
+            idle_timeout: Some(40),
+            maximum_capacity: 200,
+            maximum_values_per_key: 2,
+            time_to_live: Some(120),
+        }));
+        let query = Query {
+            qname: Name::new_unchecked("host.local"),
+            qtype: QueryType::A,
+        };
+        let old = a_response(Ipv4Addr::new(192, 168, 1, 10), false);
+        store(&cache, query.clone(), old.clone(), 120).await;
+        sleep(Duration::from_millis(1100)).await;
+
+        // a record owned by its sender pushes out the stale address within a second
+        let new = a_response(Ipv4Addr::new(192, 168, 1, 20), true);
+        store(&cache, query.clone(), new.clone(), 120).await;
+        assert_eq!(cache.get(&query).await.len(), 2);
+        sleep(Duration::from_millis(1500)).await;
+        let cached = cache.get(&query).await;
+        assert_eq!(cached.len(), 1);
+        assert_eq!(*cached[0], new);
+
+        // a goodbye removes it a second later
+        store(&cache, query.clone(), new, 0).await;
+        assert_eq!(cache.get(&query).await.len(), 1);
+        sleep(Duration::from_millis(1500)).await;
+        assert!(cache.get(&query).await.is_empty());
+    }
+
+    #[test]
+    fn test_known_answers_half_ttl() {
+        let now = SystemTime::now();
+        let response = |ttl: u64, age: u64| {
+            Arc::new(Response {
+                inner: ResponseInner::A {
+                    address: Ipv4Addr::new(192, 168, 1, 10),
+                },
+                ends_at: now - Duration::from_secs(age) + Duration::from_secs(ttl),
+                received_at: now - Duration::from_secs(age),
+                cache_flush: false,
+            })
+        };
+        let known = known_answers(
+            vec![response(120, 10), response(120, 61), response(4500, 1000)],
+            now,
+        );
+        // the copy past half its TTL is left for responders to refresh
+        let ttls: Vec<_> = known.iter().map(|(_, ttl)| *ttl).collect();
+        assert_eq!(ttls, [110, 3500]);
+    }
+
+    #[tokio::test]
+    async fn test_known_answers_split_over_packets() {
+        let cache: Cache = Arc::new(SkmvCache::new(SkmvConfig {
+            idle_timeout: Some(40),
+            maximum_capacity: 200,
+            maximum_values_per_key: 100,
+            time_to_live: Some(120),
+        }));
+        let query = Query {
+            qname: Name::new_unchecked("_homecast._tcp.local"),
+            qtype: QueryType::PTR,
+        };
+        for i in 0..60 {
+            let now = SystemTime::now();
+            let response = Response {
+                inner: ResponseInner::PTR(format!(
+                    "living-room-speaker-{}._homecast._tcp.local",
+                    i
+                )),
+                ends_at: now + Duration::from_secs(120),
+                received_at: now,
+                cache_flush: false,
+            };
+            store(&cache, query.clone(), response, 120).await;
+        }
+        let known = known_answers(cache.get(&query).await, SystemTime::now());
+        let packets = prepare_query(&query, false, &known).unwrap();
+        assert!(packets.len() > 1);
+        // packets are filled up before the next one starts
+        assert!(packets[0].len() > super::super::MAX_PACKET_SIZE - 100);
+        let mut answers = 0;
+        for (i, bytes) in packets.iter().enumerate() {
+            assert!(bytes.len() <= super::super::MAX_PACKET_SIZE);
+            let packet = Packet::parse(bytes).unwrap();
+            assert_eq!(packet.questions.len(), usize::from(i == 0));
+            assert_eq!(
+                packet.has_flags(PacketFlag::TRUNCATION),
+                i + 1 < packets.len()
+            );
+            answers += packet.answers.len();
+        }
+        assert_eq!(answers, 60);
+    }
+
+    #[test]
+    fn test_concurrent_lookups_ask_once() {
+        let asking = DashMap::new();
+        let query = Query {
+            qname: Name::new_unchecked("host.local"),
+            qtype: QueryType::A,
+        };
+        let first = Asking::start(&asking, &query);
+        let second = Asking::start(&asking, &query);
+        assert!(first.first && !second.first);
+        // the question is still out while any lookup waits for it
+        drop(first);
+        assert!(!Asking::start(&asking, &query).first);
+        drop(second);
+        assert!(asking.is_empty());
+        assert!(Asking::start(&asking, &query).first);
+    }
+
+    #[test]
+    fn test_duplicate_question_suppression() {
+        let query = Query {
+            qname: Name::new_unchecked("host.local"),
+            qtype: QueryType::A,
+        };
+        let address = Ipv4Addr::new(192, 168, 1, 10);
+        let known = [Arc::new(a_response(address, false))];
+        let mut packet = Packet::new_query(0);
+        packet.questions.push(Question::new(
+            query.qname.clone(),
+            query.qtype.clone().into(),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        ));
+        let asked = AskedQuestions::default();
+        asked.observe(2, false, &packet);
+
+        // their query lists no known answers, every response reaches us as well
+        assert!(asked.covers(&query, &known, &[(2, false)]));
+        assert!(asked.covers(&query, &[], &[(2, false)]));
+        // it has to have been asked on every link we'd send on
+        assert!(!asked.covers(&query, &known, &[(2, false), (3, false)]));
+
+        packet.answers.push(ResourceRecord::new(
+            Name::new_unchecked("HOST.local"),
+            CLASS::IN,
+            120,
+            simple_dns::rdata::RData::A(address.into()),
+        ));
+        asked.observe(3, false, &packet);
+        assert!(asked.covers(&query, &known, &[(3, false)]));
+        // their known answer would suppress a response we don't have
+        assert!(!asked.covers(&query, &[], &[(3, false)]));
+    }
+}
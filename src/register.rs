@@ -1,92 +1,378 @@
-use super::types::Instance;
-use dashmap::{DashMap, DashSet};
-use std::{
-    collections::HashMap,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    sync::Arc,
-};
+use super::error::Error;
+use super::interface::Interface;
+use super::txt::TxtRecord;
+use super::types::{Instance, ProbeSignal, Registration};
+use dashmap::{DashMap, DashSet, Entry};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{broadcast, mpsc, watch};
+
+// ownership state of a name we claimed on the link
+#[derive(Debug, Clone)]
+pub struct Claim {
+    pub instance: Instance,
+    // the registration the name is probed or owned for, it may go through several names
+    pub registration: u64,
+    // false while probing, true once the name is confirmed unique and answered for
+    pub owned: bool,
+    pub signal: mpsc::Sender<ProbeSignal>,
+    // what the registration handle of the application sees
+    pub current: watch::Sender<Instance>,
+}
+
+// changes to the records we answer for, the announcer tells the network about them
+#[derive(Debug, Clone)]
+pub enum Change {
+    Registered(Instance),
+    Updated(Instance),
+    // an owned name passed probing again on the interface with the index
+    Reprobed(Instance, u32),
+}
 
 #[derive(Debug, Clone)]
 pub struct Registry {
     devices: Arc<DashMap<String, DashSet<Instance>>>,
+    // every name we probe for or own, keyed by the lowercased instance name
+    claims: Arc<DashMap<String, Claim>>,
+    // registrations which haven't been unregistered yet
+    live: Arc<DashSet<u64>>,
+    // host name probes in progress, keyed by interface index
+    hosts: Arc<DashMap<u32, (Interface, mpsc::Sender<ProbeSignal>)>>,
+    next_registration: Arc<AtomicU64>,
+    changes: broadcast::Sender<Change>,
 }
 
 impl Registry {
     pub fn new() -> Self {
         Registry {
             devices: Arc::new(DashMap::new()),
+            claims: Arc::new(DashMap::new()),
+            live: Arc::new(DashSet::new()),
+            hosts: Arc::new(DashMap::new()),
+            next_registration: Arc::new(AtomicU64::new(1)),
+            changes: broadcast::channel(64).0,
         }
     }
 
-    pub fn get_instance_names(&self, stype: &str) -> Result<Vec<String>, String> {
-        if let Some(instances) = self.devices.get(stype) {
-            Ok(instances.iter().map(|i| i.name().to_string()).collect())
-        } else {
-            Err(format!("No instances found for service type: {}", stype))
-        }
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
     }
 
-    pub fn get_instance(&self, instance: &str) -> Result<Instance, String> {
-        let service_type = Instance::break_instance_str(instance)?;
-        if let Some(instances) = self.devices.get(&service_type) {
-            if let Some(ins) =
-                instances
-                    .value()
-                    .get(&Instance::new(instance.to_string(), 100, HashMap::new())?)
-            {
-                return Ok(ins.clone());
+    // starts a registration, its claims live until it is closed
+    pub fn open(&self) -> u64 {
+        let registration = self.next_registration.fetch_add(1, Ordering::Relaxed);
+        self.live.insert(registration);
+        registration
+    }
+
+    // Claims the name for probing, false if it is already claimed by another local registration.
+    // Fails once the registration was closed, so a rename can't outlive unregistering.
+    pub fn claim(
+        &self,
+        instance: &Instance,
+        registration: u64,
+        signal: mpsc::Sender<ProbeSignal>,
+        current: watch::Sender<Instance>,
+    ) -> Result<bool, Error> {
+        let key = instance.name().to_lowercase();
+        match self.claims.entry(key.clone()) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(Claim {
+                    instance: instance.clone(),
+                    registration,
+                    owned: false,
+                    signal,
+                    current,
+                });
             }
         }
-        Err(format!("Instance not found: {}", instance))
-    }
-
-    pub fn get_ip4_list() -> Vec<Ipv4Addr> {
-        let mut ip4_list = Vec::new();
-        let interfaces_x = local_ip_address::list_afinet_netifas();
-        if let Ok(interfaces) = interfaces_x {
-            for interface in interfaces {
-                match interface.1 {
-                    IpAddr::V4(v4) => {
-                        ip4_list.push(v4);
-                    }
-                    _ => {}
-                }
+        // checked after inserting, a concurrent close either sees the claim or we see the close
+        if !self.live.contains(&registration) {
+            self.claims.remove(&key);
+            return Err(Error::NotRegistered(instance.name().to_string()));
+        }
+        Ok(true)
+    }
+
+    // Ends the registration and releases every name it probes or owns, returning those claims.
+    pub fn close(&self, registration: u64) -> Vec<Claim> {
+        self.live.remove(&registration);
+        let names: Vec<String> = self
+            .claims
+            .iter()
+            .filter(|claim| claim.registration == registration)
+            .map(|claim| claim.key().clone())
+            .collect();
+        let mut closed = Vec::new();
+        for name in names {
+            if let Some((_, claim)) = self.claims.remove(&name) {
+                self.unregister_device(&claim.instance);
+                closed.push(claim);
             }
         }
-        ip4_list
-    }
-
-    pub fn get_ip6_list() -> Vec<Ipv6Addr> {
-        let mut ip6_list = Vec::new();
-        let interfaces_x = local_ip_address::list_afinet_netifas();
-        if let Ok(interfaces) = interfaces_x {
-            for interface in interfaces {
-                match interface.1 {
-                    IpAddr::V6(v6) => {
-                        ip6_list.push(v6);
-                    }
-                    _ => {}
-                }
+        closed
+    }
+
+    // Promotes a probed claim to owned and starts answering for it, `None` if it got released
+    // meanwhile. The claim holds the latest metadata, updates during the probe aren't lost.
+    pub fn confirm(&self, instance: &Instance) -> Option<Instance> {
+        let mut claim = self.claims.get_mut(&instance.name().to_lowercase())?;
+        claim.owned = true;
+        let instance = claim.instance.clone();
+        claim.current.send_if_modified(|current| {
+            let changed = current.name() != instance.name();
+            *current = instance.clone();
+            changed
+        });
+        self.register_device(instance.clone());
+        Some(instance)
+    }
+
+    // the name the registration currently probes or owns
+    pub fn claimed(&self, registration: u64) -> Option<Claim> {
+        self.claims
+            .iter()
+            .find(|claim| claim.registration == registration)
+            .map(|claim| claim.value().clone())
+    }
+
+    // swaps the metadata of the registration's instance, announcing it if we already own the name
+    pub fn update(&self, registration: &Registration, metadata: TxtRecord) -> Result<(), Error> {
+        let Some(mut claim) = self
+            .claims
+            .iter_mut()
+            .find(|claim| claim.registration == registration.id())
+        else {
+            return Err(Error::NotRegistered(registration.name()));
+        };
+        let instance = claim.instance.with_metadata(metadata)?;
+        claim.instance = instance.clone();
+        claim.current.send_replace(instance.clone());
+        if claim.owned {
+            // instances are compared by name, so the old entry has to go first
+            if let Some(instances) = self.devices.get(&instance.service_type().to_lowercase()) {
+                instances.remove(&instance);
+                instances.insert(instance.clone());
             }
+            let _ = self.changes.send(Change::Updated(instance));
+        }
+        Ok(())
+    }
+
+    // gives up the name, returning the claimed instance with the metadata it had last
+    pub fn release(&self, instance: &Instance) -> Option<Instance> {
+        let claim = self.claims.remove(&instance.name().to_lowercase());
+        self.unregister_device(instance);
+        claim.map(|(_, claim)| claim.instance)
+    }
+
+    // every instance we currently own and answer for
+    pub fn owned(&self) -> Vec<Instance> {
+        self.devices
+            .iter()
+            .flat_map(|instances| {
+                instances
+                    .value()
+                    .iter()
+                    .map(|i| i.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.live.clear();
+        self.claims.clear();
+        self.devices.clear();
+    }
+
+    // puts every name we own back into probing on the interface, e.g. after it got a new address
+    pub fn reprobe(&self, interface: u32) {
+        for claim in self.claims.iter().filter(|claim| claim.owned) {
+            let _ = claim.signal.try_send(ProbeSignal::Reprobe(interface));
+        }
+    }
+
+    // announces the registration's name again on the interface, 0 for every interface,
+    // once it passed probing while we kept answering for it
+    pub fn reannounce(&self, registration: u64, interface: u32) {
+        if let Some(claim) = self.claimed(registration).filter(|claim| claim.owned) {
+            let _ = self
+                .changes
+                .send(Change::Reprobed(claim.instance, interface));
         }
-        ip6_list
     }
 
-    pub fn register_device(&mut self, instance: Instance) {
-        let service_type = instance.service_type();
+    // the host name is being probed on the interface, conflicts are reported to `signal`
+    pub fn probe_host(&self, interface: Interface, signal: mpsc::Sender<ProbeSignal>) {
+        self.hosts.insert(interface.index, (interface, signal));
+    }
+
+    pub fn host_probed(&self, interface: u32) {
+        self.hosts.remove(&interface);
+    }
+
+    pub fn host_probes(&self) -> Vec<(Interface, mpsc::Sender<ProbeSignal>)> {
+        self.hosts
+            .iter()
+            .map(|probe| probe.value().clone())
+            .collect()
+    }
+
+    pub fn has_claims(&self) -> bool {
+        !self.claims.is_empty() || !self.hosts.is_empty()
+    }
+
+    pub fn get_claim(&self, name: &str) -> Option<Claim> {
+        self.claims
+            .get(&name.to_lowercase())
+            .map(|claim| claim.value().clone())
+    }
+
+    // the service types we own at least one instance of
+    pub fn service_types(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter(|instances| !instances.value().is_empty())
+            .map(|instances| instances.key().clone())
+            .collect()
+    }
+
+    pub fn get_instances(&self, stype: &str) -> Result<Vec<Instance>, Error> {
+        if let Some(instances) = self.devices.get(&stype.to_lowercase()) {
+            Ok(instances.iter().map(|i| i.clone()).collect())
+        } else {
+            Err(Error::NoInstances(stype.to_string()))
+        }
+    }
+
+    pub fn get_instance(&self, instance: &str) -> Result<Instance, Error> {
+        let service_type = Instance::break_instance_str(instance)?.to_lowercase();
+        if let Some(instances) = self.devices.get(&service_type)
+            && let Some(ins) =
+                instances
+                    .value()
+                    .get(&Instance::new(instance.to_string(), 100, TxtRecord::new())?)
+        {
+            return Ok(ins.clone());
+        }
+        Err(Error::NotRegistered(instance.to_string()))
+    }
+
+    pub fn register_device(&self, instance: Instance) {
+        // keyed like the names in queries are compared, case-insensitively
+        let service_type = instance.service_type().to_lowercase();
         {
             let instances = self.devices.entry(service_type.clone()).or_default();
-            instances.value().insert(instance);
+            instances.value().insert(instance.clone());
         }
         println!("Registered device: {:?}", self.devices);
+        // nobody listening just means there is nothing to announce to
+        let _ = self.changes.send(Change::Registered(instance));
     }
 
-    pub fn unregister_device(&mut self, instance: &Instance) {
-        if let Some(instances) = self.devices.get_mut(&instance.service_type()) {
+    pub fn unregister_device(&self, instance: &Instance) {
+        let service_type = instance.service_type().to_lowercase();
+        if let Some(instances) = self.devices.get(&service_type) {
             instances.remove(instance);
-            if instances.is_empty() {
-                self.devices.remove(&instance.service_type());
-            }
         }
+        // removing while still holding the entry above would deadlock the map
+        self.devices
+            .remove_if(&service_type, |_, instances| instances.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_releases_name_being_probed() {
+        let registry = Registry::new();
+        let instance = Instance::new(
+            "tv._homecast._tcp.local".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        let (signal, _signals) = mpsc::channel(4);
+        let (current, _receiver) = watch::channel(instance.clone());
+        let registration = registry.open();
+        assert!(
+            registry
+                .claim(&instance, registration, signal.clone(), current.clone())
+                .unwrap()
+        );
+        assert!(registry.confirm(&instance).is_some());
+
+        // a conflict moved the registration on to a new name, still being probed
+        registry.release(&instance);
+        let renamed = instance.renamed();
+        assert!(
+            registry
+                .claim(&renamed, registration, signal.clone(), current.clone())
+                .unwrap()
+        );
+        let closed = registry.close(registration);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].instance.name(), renamed.name());
+        // the probe finishing late can't bring it back
+        assert!(registry.confirm(&renamed).is_none());
+        assert!(
+            registry
+                .claim(&renamed.renamed(), registration, signal, current)
+                .is_err()
+        );
+        assert!(!registry.has_claims());
+    }
+
+    #[test]
+    fn test_update_during_probe_survives_confirm() {
+        let registry = Registry::new();
+        let instance = Instance::new(
+            "tv._homecast._tcp.local".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        let (signal, _signals) = mpsc::channel(4);
+        let (current, receiver) = watch::channel(instance.clone());
+        let id = registry.open();
+        let registration = Registration::new(id, receiver);
+        registry.claim(&instance, id, signal, current).unwrap();
+
+        let mut metadata = TxtRecord::new();
+        metadata.insert("volume", "11").unwrap();
+        registry.update(&registration, metadata).unwrap();
+        // the prober still holds the copy it started probing with
+        let confirmed = registry.confirm(&instance).unwrap();
+        assert_eq!(confirmed.metadata().get_str("volume"), Some("11"));
+        assert_eq!(
+            registration.instance().metadata().get_str("volume"),
+            Some("11")
+        );
+        let owned = registry.get_instance("tv._homecast._tcp.local").unwrap();
+        assert_eq!(owned.metadata().get_str("volume"), Some("11"));
+    }
+
+    #[test]
+    fn test_instances_found_case_insensitively() {
+        let registry = Registry::new();
+        let instance = Instance::new(
+            "Living Room TV._homecast._tcp.LOCAL".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        registry.register_device(instance);
+
+        let instances = registry.get_instances("_homecast._tcp.local").unwrap();
+        assert_eq!(instances[0].name(), "Living Room TV._homecast._tcp.local");
+        assert!(registry.get_instances("_HOMECAST._tcp.local").is_ok());
+        let found = registry
+            .get_instance("living room tv._homecast._tcp.local")
+            .unwrap();
+        assert_eq!(found.label(), "Living Room TV");
     }
 }
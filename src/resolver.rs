@@ -0,0 +1,280 @@
+use super::cache::FEED_CAPACITY;
+use super::listener::Listener;
+use super::querier::{Querier, Tracking};
+use super::txt::TxtRecord;
+use super::types::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, timeout_at};
+
+fn query(name: &str, qtype: QueryType) -> Query {
+    Query {
+        qname: parse_name(name),
+        qtype,
+    }
+}
+
+// what we learned about an instance so far, from answers and additional records alike
+struct Resolution {
+    instance: String,
+    srv: Option<(u16, String)>,
+    txt: Option<TxtRecord>,
+    // addresses per lowercased host name, they may show up before the SRV naming the host
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Resolution {
+    fn new(instance: String) -> Self {
+        Resolution {
+            instance,
+            srv: None,
+            txt: None,
+            hosts: HashMap::new(),
+        }
+    }
+
+    fn host(&self) -> Option<&str> {
+        self.srv.as_ref().map(|(_, host)| host.as_str())
+    }
+
+    fn addresses(&self) -> &[IpAddr] {
+        self.host()
+            .and_then(|host| self.hosts.get(&host.to_lowercase()))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    // one address is enough, the host may well be reachable over a single family only
+    fn complete(&self) -> bool {
+        self.srv.is_some() && self.txt.is_some() && !self.addresses().is_empty()
+    }
+
+    fn learn(&mut self, query: &Query, inner: &ResponseInner) {
+        let name = display_name(&query.qname);
+        let instance = name.eq_ignore_ascii_case(&self.instance);
+        let address = match inner {
+            ResponseInner::SRV { port, target } if instance => {
+                self.srv = Some((*port, target.clone()));
+                return;
+            }
+            ResponseInner::TXT(record) if instance => {
+                self.txt = Some(record.clone());
+                return;
+            }
+            ResponseInner::A { address } => IpAddr::V4(*address),
+            ResponseInner::AAAA { address } => IpAddr::V6(*address),
+            _ => return,
+        };
+        let addresses = self.hosts.entry(name.to_lowercase()).or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    fn into_device(self) -> Option<Device> {
+        let addresses = self.addresses().to_vec();
+        let (port, host) = self.srv?;
+        if addresses.is_empty() {
+            return None;
+        }
+        Some(Device {
+            name: self.instance,
+            port,
+            host,
+            addresses,
+            metadata: self.txt.unwrap_or_default(),
+        })
+    }
+}
+
+// Follows the queries and asks the network for those the cache can't answer, all at once.
+async fn follow(
+    querier: &Querier,
+    listener: &Listener,
+    queries: [Query; 2],
+    unicast: bool,
+    tracking: &mut Tracking<'_>,
+    resolution: &mut Resolution,
+) {
+    for query in queries {
+        tracking.track(&query);
+        let cached = querier.cached(&query).await;
+        for response in &cached {
+            resolution.learn(&query, &response.inner);
+        }
+        if cached.is_empty()
+            && let Err(e) = querier.ask(&query, unicast, listener).await
+        {
+            eprintln!("Failed to send query: {}", e);
+        }
+    }
+}
+
+// Resolves an instance to a device, asking for its SRV, TXT and addresses concurrently.
+// Finishes as soon as the SRV, the TXT and at least one address are known, at the latest
+// when the duration is up with whatever was learned by then.
+pub async fn resolve(
+    querier: &Querier,
+    listener: &Listener,
+    instance: String,
+    duration: Duration,
+    mode: QueryMode,
+) -> Option<Device> {
+    let deadline = Instant::now() + duration;
+    let unicast = mode.unicast(true);
+    let (feed, mut records) = mpsc::channel(FEED_CAPACITY);
+    // a lookup dropped halfway, e.g. by a timeout around it, untracks its queries as well
+    let mut tracking = Tracking::new(querier, feed);
+    let mut resolution = Resolution::new(instance.clone());
+    let queries = [
+        query(&instance, QueryType::SRV),
+        query(&instance, QueryType::TXT),
+    ];
+    follow(
+        querier,
+        listener,
+        queries,
+        unicast,
+        &mut tracking,
+        &mut resolution,
+    )
+    .await;
+
+    let mut host: Option<String> = None;
+    loop {
+        // the SRV names the host, or a new one after an update
+        if let Some(target) = resolution.host()
+            && host.as_deref() != Some(target)
+        {
+            let target = target.to_string();
+            let queries = [
+                query(&target, QueryType::A),
+                query(&target, QueryType::AAAA),
+            ];
+            // addresses that came along with the SRV need no extra question
+            if resolution.addresses().is_empty() {
+                follow(
+                    querier,
+                    listener,
+                    queries,
+                    unicast,
+                    &mut tracking,
+                    &mut resolution,
+                )
+                .await;
+            } else {
+                for query in &queries {
+                    tracking.track(query);
+                }
+            }
+            host = Some(target);
+        }
+        if resolution.complete() {
+            break;
+        }
+        match timeout_at(deadline, records.recv()).await {
+            Ok(Some(Some((query, response, ttl)))) => {
+                querier.remember(query.clone(), response.clone(), ttl).await;
+                // goodbyes tell us nothing about the instance
+                if ttl > 0 {
+                    resolution.learn(&query, &response.inner);
+                }
+            }
+            _ => break,
+        }
+    }
+
+    resolution.into_device()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Tracker;
+    use bazuka::{SkmvCache, SkmvConfig};
+    use dashmap::DashMap;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resolution_completes_with_one_family() {
+        let instance = "tv._homecast._tcp.local";
+        let mut resolution = Resolution::new(instance.to_string());
+        // the address in the additional section may come before the SRV is handled
+        resolution.learn(
+            &query("TV-Host.local", QueryType::AAAA),
+            &ResponseInner::AAAA {
+                address: Ipv6Addr::LOCALHOST,
+            },
+        );
+        resolution.learn(
+            &query(instance, QueryType::SRV),
+            &ResponseInner::SRV {
+                port: 8080,
+                target: "tv-host.local".to_string(),
+            },
+        );
+        assert!(!resolution.complete());
+        let mut metadata = TxtRecord::new();
+        metadata.insert("magic", "42").unwrap();
+        resolution.learn(
+            &query(instance, QueryType::TXT),
+            &ResponseInner::TXT(metadata),
+        );
+        assert!(resolution.complete());
+        // addresses of other hosts are no business of this instance
+        resolution.learn(
+            &query("radio.local", QueryType::A),
+            &ResponseInner::A {
+                address: Ipv4Addr::LOCALHOST,
+            },
+        );
+
+        let device = resolution.into_device().unwrap();
+        assert_eq!(device.port, 8080);
+        assert_eq!(device.addresses, vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+        assert_eq!(device.metadata.get_str("magic"), Some("42"));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_lookups_untrack() {
+        let tracker: Tracker = Arc::new(DashMap::new());
+        let cache = Arc::new(SkmvCache::new(SkmvConfig {
+            idle_timeout: Some(40),
+            maximum_capacity: 200,
+            maximum_values_per_key: 64,
+            time_to_live: Some(120),
+        }));
+        let listener = Listener::detached(tracker.clone());
+        let querier = Querier::new(
+            cache,
+            tracker.clone(),
+            listener.clone(),
+            Duration::from_secs(60),
+        );
+        let wait = Duration::from_millis(50);
+
+        // nobody answers, the timeouts around the lookups give up first
+        let instance = "tv._homecast._tcp.local".to_string();
+        let lookup = resolve(
+            &querier,
+            &listener,
+            instance,
+            Duration::from_secs(10),
+            QueryMode::Multicast,
+        );
+        assert!(tokio::time::timeout(wait, lookup).await.is_err());
+        assert!(tracker.is_empty());
+
+        let lookup = querier.query(
+            query("tv-host.local", QueryType::A),
+            Duration::from_secs(10),
+            false,
+            QueryMode::Multicast,
+            &listener,
+        );
+        assert!(tokio::time::timeout(wait, lookup).await.is_err());
+        assert!(tracker.is_empty());
+    }
+}
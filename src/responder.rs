@@ -1,35 +1,140 @@
+use super::error::Error;
+use super::interface::Interface;
 use super::register::Registry;
+use super::types::{Instance, ProbeSignal, display_name};
 use simple_dns::{
-    CLASS, Name, Packet, QTYPE, Question, ResourceRecord, TYPE,
+    CLASS, Name, Packet, PacketFlag, QTYPE, Question, ResourceRecord, TYPE,
     rdata::{A, AAAA, PTR, RData, SRV},
 };
-#[derive(Debug)]
+use std::cmp::Ordering;
+
+// RFC 6762 §6.7: legacy resolvers don't expect records to change, keep their caches short-lived
+const LEGACY_TTL: u32 = 10;
+
+#[derive(Debug, Clone)]
 pub struct Responder {
     registry: Registry,
+    // TTL of every record we answer or announce with
+    ttl: u32,
+}
+
+fn is_hostname(qname: &Name) -> bool {
+    qname
+        .to_string()
+        .eq_ignore_ascii_case(super::mdns_hostname())
 }
 
 impl Responder {
-    pub fn new(registry: Registry) -> Self {
-        Responder { registry }
+    pub fn new(registry: Registry, ttl: u32) -> Self {
+        Responder { registry, ttl }
+    }
+
+    fn srv_record<'a>(&self, qname: &Name<'a>, instance: &Instance) -> ResourceRecord<'a> {
+        ResourceRecord::new(
+            qname.clone(),
+            CLASS::IN,
+            self.ttl,
+            RData::SRV(SRV {
+                priority: 0,
+                weight: 0,
+                port: instance.port(),
+                target: Name::new_unchecked(super::mdns_hostname()).into_owned(),
+            }),
+        )
+    }
+
+    fn txt_record<'a>(&self, qname: &Name<'a>, instance: &Instance) -> ResourceRecord<'a> {
+        ResourceRecord::new(
+            qname.clone(),
+            CLASS::IN,
+            self.ttl,
+            RData::TXT(instance.metadata().to_txt()),
+        )
+    }
+
+    // the PTR of the service type, followed by one per subtype of the instance
+    fn ptr_records(&self, instance: &Instance) -> Vec<ResourceRecord<'static>> {
+        let service_type = instance.service_type();
+        let target = instance.qname();
+        std::iter::once(service_type.clone())
+            .chain(
+                instance
+                    .subtypes()
+                    .iter()
+                    .map(|subtype| format!("{}._sub.{}", subtype, service_type)),
+            )
+            .map(|name| {
+                ResourceRecord::new(
+                    Name::new_unchecked(&name).into_owned(),
+                    CLASS::IN,
+                    self.ttl,
+                    RData::PTR(PTR(target.clone())),
+                )
+            })
+            .collect()
+    }
+
+    // The unique records (SRV and TXT) an instance claims as its own on the network.
+    pub fn unique_records(&self, instance: &Instance) -> Vec<ResourceRecord<'static>> {
+        let qname = instance.qname();
+        vec![
+            self.srv_record(&qname, instance),
+            self.txt_record(&qname, instance),
+        ]
+    }
+
+    // The address records of the interface, which our host name claims on its link.
+    pub fn host_records(&self, interface: &Interface) -> Vec<ResourceRecord<'static>> {
+        let mut packet = Packet::new_reply(0);
+        self.inject_a_records(true, interface, &mut packet);
+        self.inject_aaaa_records(true, interface, &mut packet);
+        packet.answers
     }
 
     fn inject_ptr_records<'a>(
         &self,
         qname: &Name<'a>,
         packet: &mut Packet<'a>,
-    ) -> Result<(), String> {
-        let service_type = qname.to_string();
-        let instance_names = self.registry.get_instance_names(&service_type)?;
-        instance_names.iter().for_each(|instance_name| {
-            let record = ResourceRecord::new(
+    ) -> Result<(), Error> {
+        let name = qname.to_string();
+        // RFC 6763 §7.1: "<subtype>._sub.<service type>" lists only the instances with the subtype
+        let (subtype, service_type) = match name.split_once("._sub.") {
+            Some((subtype, service_type)) => (Some(subtype), service_type),
+            None => (None, name.as_str()),
+        };
+        let instances = self.registry.get_instances(service_type)?;
+        instances
+            .iter()
+            .filter(|instance| {
+                subtype.is_none_or(|subtype| {
+                    instance
+                        .subtypes()
+                        .iter()
+                        .any(|s| s.eq_ignore_ascii_case(subtype))
+                })
+            })
+            .for_each(|instance| {
+                let record = ResourceRecord::new(
+                    qname.clone(),
+                    CLASS::IN,
+                    self.ttl,
+                    RData::PTR(PTR(instance.qname())),
+                );
+                packet.answers.push(record);
+            });
+        Ok(())
+    }
+
+    // RFC 6763 §9: one PTR per service type we offer, for the service type enumeration query
+    fn inject_service_type_records<'a>(&self, qname: &Name<'a>, packet: &mut Packet<'a>) {
+        for service_type in self.registry.service_types() {
+            packet.answers.push(ResourceRecord::new(
                 qname.clone(),
                 CLASS::IN,
-                120,
-                RData::PTR(PTR(Name::new_unchecked(instance_name).into_owned())),
-            );
-            packet.answers.push(record);
-        });
-        Ok(())
+                self.ttl,
+                RData::PTR(PTR(Name::new_unchecked(&service_type).into_owned())),
+            ));
+        }
     }
 
     fn inject_srv_records<'a>(
@@ -37,19 +142,9 @@ impl Responder {
         ascope: bool,
         qname: &Name<'a>,
         packet: &mut Packet<'a>,
-    ) -> Result<(), String> {
-        let instance = self.registry.get_instance(&qname.to_string())?;
-        let record = ResourceRecord::new(
-            qname.clone(),
-            CLASS::IN,
-            120,
-            RData::SRV(SRV {
-                priority: 0,
-                weight: 0,
-                port: instance.port(),
-                target: Name::new_unchecked(super::mdns_hostname()).into_owned(),
-            }),
-        );
+    ) -> Result<(), Error> {
+        let instance = self.registry.get_instance(&display_name(qname))?;
+        let record = self.srv_record(qname, &instance);
         if ascope {
             packet.answers.push(record);
         } else {
@@ -63,39 +158,27 @@ impl Responder {
         ascope: bool,
         qname: &Name<'a>,
         packet: &mut Packet<'a>,
-    ) -> Result<(), String> {
-        let instance = self.registry.get_instance(&qname.to_string())?;
-        let metadata: Vec<String> = instance
-            .metadata()
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect();
-        // If metadata is empty, we do not inject a TXT record
-        if !metadata.is_empty() {
-            let txt_record = ResourceRecord::new(
-                qname.clone(),
-                CLASS::IN,
-                120,
-                RData::TXT(super::form_text_record(metadata.as_ref())),
-            );
-            if ascope {
-                packet.answers.push(txt_record);
-            } else {
-                packet.additional_records.push(txt_record);
-            }
-            return Ok(());
+    ) -> Result<(), Error> {
+        let instance = self.registry.get_instance(&display_name(qname))?;
+        let txt_record = self.txt_record(qname, &instance);
+        if ascope {
+            packet.answers.push(txt_record);
+        } else {
+            packet.additional_records.push(txt_record);
         }
-        Err(format!("Instance not found for query: {}", qname))
+        Ok(())
     }
 
-    // Injects A records into the provided packet.
-    fn inject_a_records<'a>(&self, ascope: bool, packet: &mut Packet<'a>) {
-        for ip in Registry::get_ip4_list() {
+    // Injects the A records of the interface into the provided packet.
+    fn inject_a_records<'a>(&self, ascope: bool, interface: &Interface, packet: &mut Packet<'a>) {
+        for ip in &interface.v4 {
             let record = ResourceRecord::new(
                 Name::new_unchecked(super::mdns_hostname()).into_owned(),
                 CLASS::IN,
-                120,
-                RData::A(A { address: ip.into() }),
+                self.ttl,
+                RData::A(A {
+                    address: (*ip).into(),
+                }),
             );
             if ascope {
                 packet.answers.push(record);
@@ -105,15 +188,20 @@ impl Responder {
         }
     }
 
-    // Injects AAAA records into the provided packet.
-    fn inject_aaaa_records<'a>(&self, ascope: bool, packet: &mut Packet<'a>) {
-        for ip6 in Registry::get_ip6_list() {
+    // Injects the AAAA records of the interface into the provided packet.
+    fn inject_aaaa_records<'a>(
+        &self,
+        ascope: bool,
+        interface: &Interface,
+        packet: &mut Packet<'a>,
+    ) {
+        for ip6 in &interface.v6 {
             let record = ResourceRecord::new(
                 Name::new_unchecked(super::mdns_hostname()).into_owned(),
                 CLASS::IN,
-                120,
+                self.ttl,
                 RData::AAAA(AAAA {
-                    address: ip6.into(),
+                    address: (*ip6).into(),
                 }),
             );
             if ascope {
@@ -124,12 +212,92 @@ impl Responder {
         }
     }
 
+    // Checks that the records of the instance fit into a single message, the announcement
+    // carrying all of them is the largest one we send for it.
+    pub fn check_size(&self, instance: &Instance) -> Result<(), Error> {
+        // the address records are left out of a message that doesn't fit otherwise
+        let interface = Interface {
+            name: String::new(),
+            index: 0,
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        let size = super::message_size(&self.announcement(instance, &interface));
+        match size.filter(|&size| size <= super::MAX_MESSAGE_SIZE) {
+            Some(_) => Ok(()),
+            None => Err(Error::InvalidTxtRecord(format!(
+                "The records of {} don't fit into a message of {} bytes",
+                instance.name(),
+                super::MAX_MESSAGE_SIZE
+            ))),
+        }
+    }
+
+    // Unsolicited response carrying every record of the instance (RFC 6762 §8.3).
+    pub fn announcement(&self, instance: &Instance, interface: &Interface) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.set_flags(PacketFlag::AUTHORITATIVE_ANSWER);
+        packet.answers.extend(self.unique_records(instance));
+        self.inject_a_records(true, interface, &mut packet);
+        self.inject_aaaa_records(true, interface, &mut packet);
+        // everything but the shared PTR records is unique to us, peers flush older copies
+        packet
+            .answers
+            .iter_mut()
+            .for_each(|record| record.cache_flush = true);
+        packet.answers.splice(0..0, self.ptr_records(instance));
+        packet
+    }
+
+    // Unsolicited response replacing the TXT record peers have cached for the instance (RFC 6762 §8.4).
+    pub fn txt_announcement(&self, instance: &Instance) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.set_flags(PacketFlag::AUTHORITATIVE_ANSWER);
+        let qname = instance.qname();
+        packet
+            .answers
+            .push(self.txt_record(&qname, instance).with_cache_flush(true));
+        packet
+    }
+
+    // Records of the instance with a zero TTL, telling peers to drop them (RFC 6762 §10.1).
+    pub fn goodbye(&self, instance: &Instance) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.set_flags(PacketFlag::AUTHORITATIVE_ANSWER);
+        packet.answers.extend(self.ptr_records(instance));
+        packet.answers.extend(self.unique_records(instance));
+        packet.answers.iter_mut().for_each(|record| record.ttl = 0);
+        packet
+    }
+
+    // Address records of this host with a zero TTL, sent once nothing is registered anymore.
+    pub fn host_goodbye(&self, interface: &Interface) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.set_flags(PacketFlag::AUTHORITATIVE_ANSWER);
+        packet.answers = self.host_records(interface);
+        packet.answers.iter_mut().for_each(|record| record.ttl = 0);
+        packet
+    }
+
+    // Unsolicited response with the address records of the interface, after the host name
+    // passed probing on it.
+    pub fn host_announcement(&self, interface: &Interface) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.set_flags(PacketFlag::AUTHORITATIVE_ANSWER);
+        packet.answers = self.host_records(interface);
+        packet
+            .answers
+            .iter_mut()
+            .for_each(|record| record.cache_flush = true);
+        packet
+    }
+
     // Prepares a response packet for PTR queries by injecting PTR, SRV, and TXT records.
     fn prepare_ptr_response<'a>(
         &self,
         qname: &Name<'a>,
         response_packet: &mut Packet<'a>,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.inject_ptr_records(qname, response_packet)?;
         let ptr_records: Vec<_> = response_packet
             .answers
@@ -153,45 +321,168 @@ impl Responder {
     fn prepare_srv_response<'a>(
         &self,
         qname: &Name<'a>,
+        interface: &Interface,
         response_packet: &mut Packet<'a>,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.inject_srv_records(true, qname, response_packet)?;
-        if let Some(first_srv) = response_packet.answers.first() {
-            if let RData::SRV(_) = &first_srv.rdata {
-                self.inject_a_records(false, response_packet);
-                self.inject_aaaa_records(false, response_packet);
-            }
+        if let Some(first_srv) = response_packet.answers.first()
+            && let RData::SRV(_) = &first_srv.rdata
+        {
+            self.inject_a_records(false, interface, response_packet);
+            self.inject_aaaa_records(false, interface, response_packet);
         }
         Ok(())
     }
 
-    pub fn answer_queries<'a>(&self, questions: Vec<Question<'a>>) -> Packet<'a> {
+    pub fn answer_queries<'a>(
+        &self,
+        questions: Vec<Question<'a>>,
+        interface: &Interface,
+    ) -> Packet<'a> {
         let mut response_packet = Packet::new_reply(0);
         for question in questions {
             if let QTYPE::TYPE(qtype) = question.qtype {
                 match qtype {
+                    TYPE::PTR
+                        if question
+                            .qname
+                            .to_string()
+                            .eq_ignore_ascii_case(super::SERVICE_TYPES_QUERY) =>
+                    {
+                        self.inject_service_type_records(&question.qname, &mut response_packet);
+                    }
                     TYPE::PTR => {
                         _ = self.prepare_ptr_response(&question.qname, &mut response_packet);
                     }
                     TYPE::SRV => {
-                        _ = self.prepare_srv_response(&question.qname, &mut response_packet);
+                        _ = self.prepare_srv_response(
+                            &question.qname,
+                            interface,
+                            &mut response_packet,
+                        );
                     }
                     TYPE::TXT => {
                         _ = self.inject_txt_records(true, &question.qname, &mut response_packet);
                     }
-                    TYPE::A => {
-                        self.inject_a_records(true, &mut response_packet);
+                    // only our own host name has address records
+                    TYPE::A if is_hostname(&question.qname) => {
+                        self.inject_a_records(true, interface, &mut response_packet);
                     }
-                    TYPE::AAAA => {
-                        self.inject_aaaa_records(true, &mut response_packet);
+                    TYPE::AAAA if is_hostname(&question.qname) => {
+                        self.inject_aaaa_records(true, interface, &mut response_packet);
                     }
                     _ => {}
                 }
+            } else if let QTYPE::ANY = question.qtype {
+                // probes ask for ANY, answering them defends the names we own
+                if is_hostname(&question.qname) {
+                    self.inject_a_records(true, interface, &mut response_packet);
+                    self.inject_aaaa_records(true, interface, &mut response_packet);
+                }
+                _ = self.inject_srv_records(true, &question.qname, &mut response_packet);
+                _ = self.inject_txt_records(true, &question.qname, &mut response_packet);
             }
         }
         response_packet
     }
 
+    // Answers a one-shot query of a plain DNS resolver (RFC 6762 §6.7), which echoes the query id
+    // and questions, caps the TTLs at 10 seconds and leaves out the cache-flush bits.
+    pub fn legacy_answer<'a>(
+        &self,
+        id: u16,
+        questions: Vec<Question<'a>>,
+        interface: &Interface,
+    ) -> Packet<'a> {
+        let mut packet = self.answer_queries(questions.clone(), interface);
+        packet.set_id(id);
+        packet.questions = questions;
+        packet
+            .answers
+            .iter_mut()
+            .chain(packet.additional_records.iter_mut())
+            .for_each(|record| {
+                record.ttl = record.ttl.min(LEGACY_TTL);
+                record.cache_flush = false;
+            });
+        packet
+    }
+
+    // Reports packets from other hosts which collide with the names we probe for or own.
+    pub fn detect_conflicts(&self, packet: &Packet) {
+        if !self.registry.has_claims() {
+            return;
+        }
+        if packet.has_flags(PacketFlag::RESPONSE) {
+            // any response record for a claimed name which isn't ours means someone else uses it (§9)
+            for record in packet.answers.iter().chain(&packet.additional_records) {
+                if let Some(claim) = self.registry.get_claim(&display_name(&record.name)) {
+                    let ours = self.unique_records(&claim.instance);
+                    if !ours.iter().any(|r| super::prober::same_record(r, record)) {
+                        let _ = claim.signal.try_send(ProbeSignal::Conflict);
+                    }
+                }
+            }
+        } else if !packet.name_servers.is_empty() {
+            // a simultaneous probe, the proposed records in the authority section decide who wins
+            for question in &packet.questions {
+                let name = display_name(&question.qname);
+                let Some(claim) = self.registry.get_claim(&name) else {
+                    continue;
+                };
+                // names we already own are defended by answering the probe instead
+                if claim.owned {
+                    continue;
+                }
+                let theirs: Vec<_> = packet
+                    .name_servers
+                    .iter()
+                    .filter(|r| display_name(&r.name).eq_ignore_ascii_case(&name))
+                    .cloned()
+                    .collect();
+                if theirs.is_empty() {
+                    continue;
+                }
+                let ours = self.unique_records(&claim.instance);
+                if super::prober::tie_break(&ours, &theirs) == Ordering::Less {
+                    let _ = claim.signal.try_send(ProbeSignal::Lost);
+                }
+            }
+        }
+        self.detect_host_conflicts(packet);
+    }
+
+    // The same for the host name while it is probed on an interface, its records are the
+    // addresses of that interface.
+    fn detect_host_conflicts(&self, packet: &Packet) {
+        for (interface, signal) in self.registry.host_probes() {
+            let ours = self.host_records(&interface);
+            if packet.has_flags(PacketFlag::RESPONSE) {
+                let conflict = packet
+                    .answers
+                    .iter()
+                    .chain(&packet.additional_records)
+                    .filter(|record| is_hostname(&record.name))
+                    .any(|record| !ours.iter().any(|r| super::prober::same_record(r, record)));
+                if conflict {
+                    let _ = signal.try_send(ProbeSignal::Conflict);
+                }
+            } else if packet.questions.iter().any(|q| is_hostname(&q.qname)) {
+                let theirs: Vec<_> = packet
+                    .name_servers
+                    .iter()
+                    .filter(|record| is_hostname(&record.name))
+                    .cloned()
+                    .collect();
+                if !theirs.is_empty() && super::prober::tie_break(&ours, &theirs) == Ordering::Less
+                {
+                    let _ = signal.try_send(ProbeSignal::Lost);
+                }
+            }
+        }
+    }
+
+    // RFC 6762 §7.1: answers the querier already knows with at least half the TTL we'd send are left out
     pub fn suppress_known_answers<'a>(
         prepared_answers: &mut Vec<ResourceRecord<'a>>,
         known_answers: &[ResourceRecord<'a>],
@@ -220,3 +511,160 @@ impl Responder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_dns::{CLASS, QCLASS};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn eth0(v4: &[Ipv4Addr], v6: &[Ipv6Addr]) -> Interface {
+        Interface {
+            name: "eth0".to_string(),
+            index: 2,
+            v4: v4.to_vec(),
+            v6: v6.to_vec(),
+        }
+    }
+
+    // a responder answering for the instances, each given with its subtypes
+    fn responder_for(instances: &[(&str, &[&str])]) -> Responder {
+        let registry = Registry::new();
+        for (name, subtypes) in instances {
+            let instance = Instance::new(name.to_string(), 8080, Default::default())
+                .and_then(|instance| instance.with_subtypes(subtypes.iter().copied()))
+                .unwrap();
+            registry.register_device(instance);
+        }
+        Responder::new(registry, 120)
+    }
+
+    #[test]
+    fn test_answer_with_interface_addresses() {
+        let responder = Responder::new(Registry::new(), 120);
+        let interface = eth0(
+            &[Ipv4Addr::new(192, 168, 1, 2)],
+            &["fe80::1".parse::<Ipv6Addr>().unwrap()],
+        );
+        let question = Question::new(
+            Name::new_unchecked(super::super::mdns_hostname()),
+            QTYPE::TYPE(TYPE::A),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        );
+        let packet = responder.answer_queries(vec![question], &interface);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(matches!(
+            &packet.answers[0].rdata,
+            RData::A(a) if Ipv4Addr::from(a.address) == interface.v4[0]
+        ));
+
+        // someone else's host name isn't ours to answer for
+        let question = Question::new(
+            Name::new_unchecked("other-host.local"),
+            QTYPE::TYPE(TYPE::AAAA),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        );
+        assert!(
+            responder
+                .answer_queries(vec![question], &interface)
+                .answers
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_legacy_answer() {
+        let responder = Responder::new(Registry::new(), 120);
+        let interface = eth0(&[Ipv4Addr::new(192, 168, 1, 2)], &[]);
+        let question = Question::new(
+            Name::new_unchecked(super::super::mdns_hostname()),
+            QTYPE::TYPE(TYPE::A),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        );
+        let packet = responder.legacy_answer(0x1234, vec![question], &interface);
+        assert_eq!(packet.id(), 0x1234);
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(
+            packet.questions[0].qname.to_string(),
+            *super::super::mdns_hostname()
+        );
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].ttl, LEGACY_TTL);
+        assert!(!packet.answers[0].cache_flush);
+    }
+
+    #[test]
+    fn test_host_name_probe_conflict() {
+        let registry = Registry::new();
+        let responder = Responder::new(registry.clone(), 120);
+        let interface = eth0(&[Ipv4Addr::new(192, 168, 1, 2)], &[]);
+        let (signal, mut signals) = tokio::sync::mpsc::channel(4);
+        registry.probe_host(interface.clone(), signal);
+
+        // a response repeating our own address isn't a conflict, another address for our name is
+        let mut packet = Packet::new_reply(0);
+        packet.answers = responder.host_records(&interface);
+        responder.detect_conflicts(&packet);
+        assert!(signals.try_recv().is_err());
+        let other = eth0(&[Ipv4Addr::new(192, 168, 1, 9)], &[]);
+        packet.answers = responder.host_records(&other);
+        responder.detect_conflicts(&packet);
+        assert_eq!(signals.try_recv(), Ok(ProbeSignal::Conflict));
+
+        registry.host_probed(interface.index);
+        responder.detect_conflicts(&packet);
+        assert!(signals.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_service_type_enumeration() {
+        let responder = responder_for(&[("tv._homecast._tcp.local", &[])]);
+        let question = Question::new(
+            Name::new_unchecked("_Services._dns-sd._udp.local"),
+            QTYPE::TYPE(TYPE::PTR),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        );
+        let interface = eth0(&[], &[]);
+        let packet = responder.answer_queries(vec![question], &interface);
+        assert_eq!(packet.answers.len(), 1);
+        let RData::PTR(PTR(service_type)) = &packet.answers[0].rdata else {
+            panic!("expected a PTR record");
+        };
+        assert_eq!(service_type.to_string(), "_homecast._tcp.local");
+    }
+
+    #[test]
+    fn test_subtype_ptr_answer() {
+        let responder = responder_for(&[
+            ("kitchen._homecast._tcp.local", &["_speaker"]),
+            ("living._homecast._tcp.local", &["_display"]),
+        ]);
+        let interface = eth0(&[], &[]);
+        let ptr_targets = |qname: &'static str| {
+            let question = Question::new(
+                Name::new_unchecked(qname),
+                QTYPE::TYPE(TYPE::PTR),
+                QCLASS::CLASS(CLASS::IN),
+                false,
+            );
+            let packet = responder.answer_queries(vec![question], &interface);
+            packet
+                .answers
+                .iter()
+                .filter_map(|record| match &record.rdata {
+                    RData::PTR(PTR(target)) => Some(target.to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ptr_targets("_speaker._sub._homecast._tcp.local"),
+            vec!["kitchen._homecast._tcp.local"]
+        );
+        assert_eq!(ptr_targets("_homecast._tcp.local").len(), 2);
+    }
+}
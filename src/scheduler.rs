@@ -0,0 +1,254 @@
+use super::error::Error;
+use super::limiter::RateLimiter;
+use super::listener::Listener;
+use super::types::{ChannelMessage, MulticastStats};
+use rand::{Rng, rng};
+use simple_dns::{Packet, ResourceRecord, rdata::RData};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+
+// RFC 6762 §6: answers with shared records wait 20-120 ms so responders don't all fire at once
+const SHARED_DELAY_MS: std::ops::RangeInclusive<u64> = 20..=120;
+
+// shared records are owned by many hosts, anything else we answer for is unique to us
+pub fn is_shared(record: &ResourceRecord) -> bool {
+    matches!(record.rdata, RData::PTR(_))
+}
+
+fn push_unique(records: &mut Vec<ResourceRecord<'static>>, record: ResourceRecord<'static>) {
+    let duplicate = records.iter().any(|r| {
+        r.name
+            .to_string()
+            .eq_ignore_ascii_case(&record.name.to_string())
+            && super::prober::same_record(r, &record)
+    });
+    if !duplicate {
+        records.push(record);
+    }
+}
+
+// Delays multicast answers holding shared records and folds the answers to every query
+// arriving in the meantime into a single response per interface and address family.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    pending: Arc<Mutex<HashMap<(u32, bool), Packet<'static>>>>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Scheduler {
+    // Sends the unique part of the response right away and schedules the shared part,
+    // `probe_defense` when the query was a probe for names we answer for.
+    pub async fn respond(
+        &self,
+        listener: &Arc<Listener>,
+        interface: u32,
+        v6: bool,
+        response: Packet<'_>,
+        probe_defense: bool,
+    ) -> Result<(), Error> {
+        let (shared, mut unique): (Vec<_>, Vec<_>) =
+            response.answers.into_iter().partition(is_shared);
+        self.limiter
+            .admit(interface, v6, &mut unique, probe_defense);
+        // the additional records go out once, with the unique answers if any are sent right away
+        let (additional, held_back) = if unique.is_empty() {
+            (Vec::new(), response.additional_records)
+        } else {
+            (response.additional_records, Vec::new())
+        };
+        if !shared.is_empty() {
+            self.schedule(
+                listener,
+                interface,
+                v6,
+                shared.into_iter().map(|r| r.into_owned()).collect(),
+                held_back.into_iter().map(|r| r.into_owned()).collect(),
+            );
+        }
+        if unique.is_empty() {
+            return Ok(());
+        }
+        let mut packet = Packet::new_reply(0);
+        packet.answers = unique;
+        packet.additional_records = additional;
+        let Some(bytes) = super::serialize_packet(&mut packet) else {
+            return Ok(());
+        };
+        listener
+            .send(ChannelMessage {
+                ip: group(v6),
+                bytes,
+                interface,
+            })
+            .await
+    }
+
+    fn schedule(
+        &self,
+        listener: &Arc<Listener>,
+        interface: u32,
+        v6: bool,
+        answers: Vec<ResourceRecord<'static>>,
+        additional: Vec<ResourceRecord<'static>>,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        let packet = match pending.entry((interface, v6)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // the first query of the window decides when the aggregate goes out
+                let delay = Duration::from_millis(rng().random_range(SHARED_DELAY_MS));
+                let pending = self.pending.clone();
+                let limiter = self.limiter.clone();
+                let listener = listener.clone();
+                tokio::spawn(async move {
+                    sleep(delay).await;
+                    let packet = pending.lock().unwrap().remove(&(interface, v6));
+                    if let Some(mut packet) = packet {
+                        limiter.admit(interface, v6, &mut packet.answers, false);
+                        // other hosts may have given every answer in the meantime
+                        if !packet.answers.is_empty() {
+                            flush(&listener, interface, v6, &mut packet).await;
+                        }
+                    }
+                });
+                entry.insert(Packet::new_reply(0))
+            }
+        };
+        for record in answers {
+            push_unique(&mut packet.answers, record);
+        }
+        for record in additional {
+            push_unique(&mut packet.additional_records, record);
+        }
+    }
+
+    // Drops pending answers another host just multicast on the link, as long as
+    // its copy lives at least half as long as ours would.
+    pub fn observe_response(&self, interface: u32, v6: bool, response: &Packet) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(packet) = pending.get_mut(&(interface, v6)) else {
+            return;
+        };
+        packet.answers.retain(|ours| {
+            !response.answers.iter().any(|theirs| {
+                theirs
+                    .name
+                    .to_string()
+                    .eq_ignore_ascii_case(&ours.name.to_string())
+                    && super::prober::same_record(ours, theirs)
+                    && theirs.ttl >= ours.ttl / 2
+            })
+        });
+    }
+
+    pub fn stats(&self) -> MulticastStats {
+        self.limiter.stats()
+    }
+}
+
+fn group(v6: bool) -> std::net::SocketAddr {
+    if v6 {
+        *super::multicast_addr_v6()
+    } else {
+        *super::multicast_addr_v4()
+    }
+}
+
+async fn flush(listener: &Listener, interface: u32, v6: bool, packet: &mut Packet<'static>) {
+    let Some(bytes) = super::serialize_packet(packet) else {
+        return;
+    };
+    let message = ChannelMessage {
+        ip: group(v6),
+        bytes,
+        interface,
+    };
+    if let Err(e) = listener.send(message).await {
+        eprintln!("Failed to send delayed response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dashmap::DashMap;
+    use simple_dns::{CLASS, Name, rdata::A, rdata::PTR, rdata::SRV};
+
+    fn ptr(instance: &'static str) -> ResourceRecord<'static> {
+        ResourceRecord::new(
+            Name::new_unchecked("_homecast._tcp.local"),
+            CLASS::IN,
+            120,
+            RData::PTR(PTR(Name::new_unchecked(instance))),
+        )
+    }
+
+    fn response(answers: Vec<ResourceRecord<'static>>) -> Packet<'static> {
+        let mut packet = Packet::new_reply(0);
+        packet.answers = answers;
+        packet.additional_records.push(ResourceRecord::new(
+            Name::new_unchecked("host.local"),
+            CLASS::IN,
+            120,
+            RData::A(A {
+                address: 0x0a090002,
+            }),
+        ));
+        packet
+    }
+
+    fn pending(scheduler: &Scheduler) -> Option<Packet<'static>> {
+        scheduler.pending.lock().unwrap().get(&(2, false)).cloned()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_answers_delayed_and_aggregated() {
+        let listener = Listener::detached(Arc::new(DashMap::new()));
+        let scheduler = Scheduler::default();
+        let tv = response(vec![ptr("tv._homecast._tcp.local")]);
+        let _ = scheduler
+            .respond(&listener, 2, false, tv.clone(), false)
+            .await;
+        // a second query within the window joins the same response, without duplicates
+        let speaker = response(vec![ptr("speaker._homecast._tcp.local")]);
+        let _ = scheduler.respond(&listener, 2, false, speaker, false).await;
+        let _ = scheduler.respond(&listener, 2, false, tv, false).await;
+        let packet = pending(&scheduler).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.additional_records.len(), 1);
+
+        // RFC 6762 §6: held back for 20-120 ms
+        sleep(Duration::from_millis(19)).await;
+        assert!(pending(&scheduler).is_some());
+        sleep(Duration::from_millis(102)).await;
+        assert!(pending(&scheduler).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unique_answers_sent_with_additional_records() {
+        let listener = Listener::detached(Arc::new(DashMap::new()));
+        let scheduler = Scheduler::default();
+        let srv = ResourceRecord::new(
+            Name::new_unchecked("tv._homecast._tcp.local"),
+            CLASS::IN,
+            120,
+            RData::SRV(SRV {
+                priority: 0,
+                weight: 0,
+                port: 8080,
+                target: Name::new_unchecked("host.local"),
+            }),
+        );
+        let packet = response(vec![ptr("tv._homecast._tcp.local"), srv]);
+        let _ = scheduler.respond(&listener, 2, false, packet, false).await;
+        // the SRV left right away, only the PTR waits, the additional records aren't repeated
+        let packet = pending(&scheduler).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert!(is_shared(&packet.answers[0]));
+        assert!(packet.additional_records.is_empty());
+        assert_eq!(scheduler.stats().sent, 1);
+    }
+}
@@ -0,0 +1,216 @@
+use super::error::Error;
+use simple_dns::CharacterString;
+use simple_dns::rdata::TXT;
+
+// RFC 6763 §6.1: a record of up to 8900 bytes still fits into an mDNS message, whether it
+// does along with the names of the instance is checked when registering
+const MAX_RECORD_SIZE: usize = 8900;
+// every attribute is a single character string, its length has to fit into one byte
+const MAX_STRING_SIZE: usize = 255;
+
+/// The attributes of a DNS-SD TXT record (RFC 6763 §6).
+///
+/// Keys are printable ASCII without `=` and compared case-insensitively. A key is either
+/// present on its own, a boolean attribute, or carries a value of arbitrary bytes, which may
+/// be empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TxtRecord {
+    // in the order of the strings on the wire, a key shows up at most once
+    entries: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl TxtRecord {
+    pub fn new() -> Self {
+        TxtRecord::default()
+    }
+
+    /// Sets `key` to `value`, replacing what the key held before.
+    pub fn insert(&mut self, key: &str, value: impl AsRef<[u8]>) -> Result<(), Error> {
+        self.set(key, Some(value.as_ref().to_vec()))
+    }
+    /// Sets the boolean attribute `key`, a key without a value on the wire.
+    pub fn insert_flag(&mut self, key: &str) -> Result<(), Error> {
+        self.set(key, None)
+    }
+    /// Removes `key`, returns whether it was present.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.entries.len() != before
+    }
+
+    /// Whether `key` is present, with or without a value (RFC 6763 §6.4).
+    pub fn get_bool(&self, key: &str) -> bool {
+        self.entry(key).is_some()
+    }
+    /// The value of `key`, `None` if it is missing or a boolean attribute.
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.entry(key)?.as_deref()
+    }
+    /// The value of `key` if it is valid UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        std::str::from_utf8(self.get_bytes(key)?).ok()
+    }
+
+    /// The attributes in record order, `None` as value for boolean ones.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&[u8]>)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_deref()))
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entry(&self, key: &str) -> Option<&Option<Vec<u8>>> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
+    fn set(&mut self, key: &str, value: Option<Vec<u8>>) -> Result<(), Error> {
+        // RFC 6763 §6.4: at least one printable US-ASCII character, '=' excluded
+        if key.is_empty() || !key.bytes().all(|b| (0x20..=0x7e).contains(&b) && b != b'=') {
+            return Err(Error::InvalidTxtRecord(format!(
+                "Key '{}' should be printable ASCII without '='",
+                key
+            )));
+        }
+        let size = key.len() + value.as_ref().map_or(0, |value| value.len() + 1);
+        if size > MAX_STRING_SIZE {
+            return Err(Error::InvalidTxtRecord(format!(
+                "Attribute '{}' is {} bytes, at most {} fit",
+                key, size, MAX_STRING_SIZE
+            )));
+        }
+        match self
+            .entries
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+        Ok(())
+    }
+
+    // the "key=value" or "key" character strings of the record
+    fn strings(&self) -> impl Iterator<Item = Vec<u8>> {
+        self.entries.iter().map(|(key, value)| {
+            let mut string = key.as_bytes().to_vec();
+            if let Some(value) = value {
+                string.push(b'=');
+                string.extend_from_slice(value);
+            }
+            string
+        })
+    }
+
+    // size of the rdata on the wire, every string preceded by its length byte
+    fn size(&self) -> usize {
+        self.strings()
+            .map(|string| string.len() + 1)
+            .sum::<usize>()
+            .max(1)
+    }
+
+    // checked when registering, a record this large doesn't belong in a single message
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.size() > MAX_RECORD_SIZE {
+            return Err(Error::InvalidTxtRecord(format!(
+                "Record is {} bytes, at most {} are allowed",
+                self.size(),
+                MAX_RECORD_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_txt(&self) -> TXT<'static> {
+        let mut txt = TXT::new();
+        // RFC 6763 §6.1: a TXT record without attributes still holds a single empty string
+        if self.is_empty() {
+            txt.add_char_string(CharacterString::new(&[]).unwrap().into_owned());
+        }
+        for string in self.strings() {
+            // keys and values were checked on insert, a string always fits
+            if let Ok(string) = CharacterString::new(&string) {
+                txt.add_char_string(string.into_owned());
+            }
+        }
+        txt
+    }
+
+    // Parses the rdata of a received TXT record. Following RFC 6763 §6.4, empty strings and
+    // strings without a key are skipped and only the first occurrence of a key counts.
+    pub(crate) fn from_wire(rdata: &[u8]) -> Self {
+        let mut record = TxtRecord::new();
+        let mut rest = rdata;
+        while let Some((&len, tail)) = rest.split_first() {
+            let (string, tail) = tail.split_at((len as usize).min(tail.len()));
+            rest = tail;
+            let (key, value) = match string.iter().position(|&b| b == b'=') {
+                Some(at) => (&string[..at], Some(string[at + 1..].to_vec())),
+                None => (string, None),
+            };
+            let key = String::from_utf8_lossy(key);
+            if key.is_empty() || record.entry(&key).is_some() {
+                continue;
+            }
+            record.entries.push((key.into_owned(), value));
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_dns::{CLASS, Name, ResourceRecord, rdata::RData};
+
+    #[test]
+    fn test_txt_record_attributes() {
+        let mut record = TxtRecord::new();
+        record.insert("path", "/api?a=b").unwrap();
+        record.insert_flag("secure").unwrap();
+        record.insert("empty", "").unwrap();
+        record.insert("blob", [0xff, 0x00, 0x3d]).unwrap();
+        // keys are case-insensitive, the value is replaced in place
+        record.insert("PATH", "/v2?a=b").unwrap();
+        assert!(record.insert("a=b", "c").is_err());
+        assert!(record.insert("", "c").is_err());
+        assert!(record.insert("big", [0; 252]).is_err());
+
+        let parsed = TxtRecord::from_wire(&crate::rdata_bytes(&ResourceRecord::new(
+            Name::new_unchecked("tv._homecast._tcp.local"),
+            CLASS::IN,
+            120,
+            RData::TXT(record.to_txt()),
+        )));
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.get_str("Path"), Some("/v2?a=b"));
+        assert!(parsed.get_bool("secure"));
+        assert_eq!(parsed.get_bytes("secure"), None);
+        assert_eq!(parsed.get_str("empty"), Some(""));
+        assert_eq!(parsed.get_bytes("blob"), Some(&[0xff, 0x00, 0x3d][..]));
+        assert_eq!(parsed.get_str("blob"), None);
+        assert!(!parsed.get_bool("missing"));
+
+        // only the first occurrence of a key counts, strings without a key are skipped
+        let parsed = TxtRecord::from_wire(b"\x03a=1\x03A=2\x02=x\x00\x01b");
+        assert_eq!(parsed.get_str("a"), Some("1"));
+        assert!(parsed.get_bool("b"));
+        assert_eq!(parsed.len(), 2);
+
+        let mut large = TxtRecord::new();
+        for i in 0..40 {
+            large.insert(&format!("k{}", i), [b'x'; 240]).unwrap();
+        }
+        assert!(large.validate().is_err());
+        assert!(TxtRecord::new().validate().is_ok());
+    }
+}
@@ -1,10 +1,12 @@
-use simple_dns::{Name, QTYPE, TYPE, rdata::*};
+use super::error::Error;
+use super::txt::TxtRecord;
+use simple_dns::{Label, Name, QTYPE, TYPE, rdata::*};
 use std::time::SystemTime;
 use std::{
-    collections::HashMap,
     hash::{Hash, Hasher},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
+use tokio::sync::watch;
 
 #[derive(Debug)]
 pub struct Device {
@@ -12,131 +14,396 @@ pub struct Device {
     pub port: u16,
     pub host: String,
     pub addresses: Vec<IpAddr>,
-    pub metadata: HashMap<String, String>,
+    pub metadata: TxtRecord,
 }
 
 #[derive(Debug, Clone)]
 pub struct Instance {
+    // presentation form of the full name, dots and backslashes inside the label escaped
     name: String,
+    // RFC 6763 §4.1.1: the user-visible label, any UTF-8 of up to 63 bytes
+    label: String,
+    // "_homecast._tcp"
+    service_type: String,
+    domain: String,
     port: u16,
-    metadata: HashMap<String, String>,
+    metadata: TxtRecord,
+    // RFC 6763 §7.1: labels like "_speaker" the instance can also be browsed by
+    subtypes: Vec<String>,
 }
 
 impl Instance {
-    // constructor for Instance
-    pub fn new(name: String, port: u16, metadata: HashMap<String, String>) -> Result<Self, String> {
-        Self::validate(&name, port)?;
+    /// Parses a full instance name like `Living Room TV._homecast._tcp.local`. The last three
+    /// labels are the service type and the domain, everything before them is the instance
+    /// label, dots included; `\.` and `\\` escapes are understood as well.
+    pub fn new(name: String, port: u16, metadata: TxtRecord) -> Result<Self, Error> {
+        let (label, service_type, domain) = Self::split(&name)?;
+        Self::from_parts(label, service_type, domain, port, metadata)
+    }
+    /// Builds an instance from its label, e.g. `Living Room TV`, its service type, e.g.
+    /// `_homecast._tcp`, and its domain, which has to be `local`.
+    pub fn from_parts(
+        label: String,
+        service_type: String,
+        domain: String,
+        port: u16,
+        metadata: TxtRecord,
+    ) -> Result<Self, Error> {
+        Self::validate(&label, &service_type, &domain, port)?;
+        metadata.validate()?;
+        // any case is accepted, the name we answer with is spelled the usual way
+        let domain = domain.to_lowercase();
         Ok(Instance {
-            name,
+            name: format!("{}.{}.{}", escape_label(&label), service_type, domain),
+            label,
+            service_type,
+            domain,
             port,
             metadata,
+            subtypes: Vec::new(),
         })
     }
-    // getter for service type
+    /// Adds subtypes the instance can be browsed by besides its service type, e.g. `_speaker`
+    /// to find it with `_speaker._sub._homecast._tcp.local` (RFC 6763 §7.1).
+    pub fn with_subtypes<I, S>(mut self, subtypes: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        for subtype in subtypes {
+            let subtype = subtype.into();
+            if subtype.is_empty() || subtype.len() > 63 || subtype.contains('.') {
+                return Err(Error::InvalidInstance(format!(
+                    "Subtype '{}' should be a single label of 1 to 63 bytes",
+                    subtype
+                )));
+            }
+            if !self
+                .subtypes
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(&subtype))
+            {
+                self.subtypes.push(subtype);
+            }
+        }
+        Ok(self)
+    }
+    /// The service type together with the domain, e.g. `_homecast._tcp.local`.
     pub fn service_type(&self) -> String {
-        self.name[self.name.find('.').unwrap() + 1..].to_string()
+        format!("{}.{}", self.service_type, self.domain)
     }
-    // getters for name, port, and metadata
+    /// The full name in presentation form, e.g. `Living Room TV._homecast._tcp.local`.
     pub fn name(&self) -> &str {
         &self.name
     }
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
     pub fn port(&self) -> u16 {
         self.port
     }
-    pub fn metadata(&self) -> &HashMap<String, String> {
+    pub fn metadata(&self) -> &TxtRecord {
         &self.metadata
     }
+    pub fn subtypes(&self) -> &[String] {
+        &self.subtypes
+    }
+
+    // the name on the wire, with the instance label as a single label whatever it contains
+    pub(crate) fn qname(&self) -> Name<'static> {
+        let mut labels = vec![Label::new_unchecked(self.label.as_bytes().to_vec())];
+        labels.extend(
+            self.service_type
+                .split('.')
+                .chain(self.domain.split('.'))
+                .map(|label| Label::new_unchecked(label.as_bytes().to_vec())),
+        );
+        Name::new_with_labels(&labels)
+    }
+
+    pub(crate) fn with_metadata(&self, metadata: TxtRecord) -> Result<Instance, Error> {
+        metadata.validate()?;
+        Ok(Instance {
+            metadata,
+            ..self.clone()
+        })
+    }
+
+    // next candidate after a name conflict: "tv" -> "tv-2" -> "tv-3" ...
+    pub(crate) fn renamed(&self) -> Instance {
+        let (base, next) = match self.label.rsplit_once('-') {
+            Some((base, n)) if n.parse::<u32>().is_ok_and(|n| n >= 2) => {
+                (base, n.parse::<u32>().unwrap() + 1)
+            }
+            _ => (self.label.as_str(), 2),
+        };
+        let suffix = format!("-{}", next);
+        // cut the base on a character boundary to keep the label within 63 bytes
+        let mut end = base.len().min(63 - suffix.len());
+        while !base.is_char_boundary(end) {
+            end -= 1;
+        }
+        let label = format!("{}{}", &base[..end], suffix);
+        Instance {
+            name: format!(
+                "{}.{}.{}",
+                escape_label(&label),
+                self.service_type,
+                self.domain
+            ),
+            label,
+            ..self.clone()
+        }
+    }
+
+    // the service type with its domain, e.g. "_homecast._tcp.local"
+    pub fn break_instance_str(instance: &str) -> Result<String, Error> {
+        let (_, service_type, domain) = Self::split(instance)?;
+        Ok(format!("{}.{}", service_type, domain))
+    }
 
-    pub fn break_instance_str(instance: &str) -> Result<String, String> {
-        let parts: Vec<&str> = instance.split('.').collect();
-        if parts.len() < 4 {
-            return Err(format!(
+    // splits a full name into instance label, service type and domain, counting from the end
+    // so dots in the instance label stay part of it
+    fn split(name: &str) -> Result<(String, String, String), Error> {
+        let mut labels = split_labels(name);
+        if labels.iter().any(String::is_empty) {
+            return Err(Error::InvalidInstance(format!(
+                "Instance name '{}' should not contain empty labels",
+                name
+            )));
+        }
+        if labels.len() < 4 {
+            return Err(Error::InvalidInstance(
                 "Instance name must be in the format `name.service_type.protocol.local`."
+                    .to_string(),
             ));
         }
-        let service_type = format!("{}.{}.{}", parts[1], parts[2], parts[3]);
-        Ok(service_type)
+        let domain = labels.pop().unwrap();
+        let protocol = labels.pop().unwrap();
+        let service = labels.pop().unwrap();
+        Ok((
+            labels.join("."),
+            format!("{}.{}", service, protocol),
+            domain,
+        ))
     }
 
-    //validate the name format and port number
-    fn validate(name: &str, port: u16) -> Result<(), String> {
-        if name.is_empty() || !name.contains('.') {
-            return Self::is_valid_name(name);
+    //validate the labels and port number
+    fn validate(label: &str, service_type: &str, domain: &str, port: u16) -> Result<(), Error> {
+        Self::is_valid_label(label)?;
+        Self::is_valid_service_type(service_type)?;
+        if !domain.eq_ignore_ascii_case("local") {
+            return Err(Error::InvalidInstance(
+                "Domain should be 'local'".to_string(),
+            ));
         }
         if port == 0 {
-            return Err("Invalid port number".to_string());
+            return Err(Error::InvalidInstance("Invalid port number".to_string()));
         }
         Ok(())
     }
 
-    fn is_valid_name(name: &str) -> Result<(), String> {
-        // 1. No spaces
-        if name.contains(' ') {
-            return Err("Instance name should not contain spaces".to_string());
+    fn is_valid_label(label: &str) -> Result<(), Error> {
+        // RFC 6763 §4.1.1: up to 63 bytes of UTF-8, spaces and dots are fine
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::InvalidInstance(
+                "Instance name should be 1 to 63 bytes long".to_string(),
+            ));
         }
-
-        // 2. Split into exactly 4 parts
-        let parts: Vec<&str> = name.split('.').collect();
-        if parts.len() != 4 {
-            return Err(
-                "Instance name should be of format 'name._service-type._protocol._domain'"
-                    .to_string(),
-            );
+        // control characters are not allowed
+        if label.chars().any(char::is_control) {
+            return Err(Error::InvalidInstance(
+                "Instance name should not contain control characters".to_string(),
+            ));
         }
+        Ok(())
+    }
 
-        // 3. Instance name: lowercase alphanumeric with hyphens
-        let is_valid = |s: &str| {
-            s.chars()
-                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    fn is_valid_service_type(service_type: &str) -> Result<(), Error> {
+        let Some((service, protocol)) = service_type.split_once('.') else {
+            return Err(Error::InvalidInstance(
+                "Service type should be of format '_service-type._protocol'".to_string(),
+            ));
         };
-        if !is_valid(parts[0]) {
-            return Err(
-                "Instance name should be a lowercase alphanumeric string with hyphens".to_string(),
-            );
-        }
 
-        // 4. Service type: starts with '_', rest is lowercase alphanumeric with hyphens
-        if !parts[1].starts_with('_') {
-            return Err("Service type should start with an underscore".to_string());
-        }
-        let service_name = &parts[1][1..]; // Skip the '_'
+        // Service: starts with '_', rest is lowercase alphanumeric with hyphens
+        let Some(service_name) = service.strip_prefix('_') else {
+            return Err(Error::InvalidInstance(
+                "Service type should start with an underscore".to_string(),
+            ));
+        };
         if service_name.is_empty() {
-            return Err("Service type should not be empty after the underscore".to_string());
+            return Err(Error::InvalidInstance(
+                "Service type should not be empty after the underscore".to_string(),
+            ));
         }
-        if !is_valid(service_name) {
-            return Err(
+        if !service_name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(Error::InvalidInstance(
                 "Service type should be a lowercase alphanumeric string with hyphens".to_string(),
-            );
+            ));
+        }
+
+        // Protocol: exactly '_tcp' or '_udp'
+        if protocol != "_tcp" && protocol != "_udp" {
+            return Err(Error::InvalidInstance(
+                "Protocol should be either '_tcp' or '_udp'".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// RFC 6763 §4.3: dots and backslashes inside a label are escaped in the presentation form
+fn escape_label(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        if c == '.' || c == '\\' {
+            escaped.push('\\');
         }
+        escaped.push(c);
+    }
+    escaped
+}
 
-        // 5. Protocol: exactly '_tcp' or '_udp'
-        if parts[2] != "_tcp" && parts[2] != "_udp" {
-            return Err("Protocol should be either '_tcp' or '_udp'".to_string());
+// the labels of a name in presentation form, split on unescaped dots
+fn split_labels(name: &str) -> Vec<String> {
+    let mut labels = Vec::new();
+    let mut label = String::new();
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => label.extend(chars.next()),
+            '.' => labels.push(std::mem::take(&mut label)),
+            c => label.push(c),
         }
+    }
+    labels.push(label);
+    labels
+}
+
+// Builds a name from its presentation form, dots escaped inside a label don't split it.
+pub(crate) fn parse_name(name: &str) -> Name<'static> {
+    let labels: Vec<Label> = split_labels(name)
+        .into_iter()
+        // a trailing dot stands for the root, which isn't a label of its own
+        .filter(|label| !label.is_empty())
+        .map(|label| Label::new_unchecked(label.into_bytes()))
+        .collect();
+    Name::new_with_labels(&labels)
+}
+
+// The presentation form of a name, unlike its `Display` which loses dots inside labels.
+pub(crate) fn display_name(name: &Name) -> String {
+    name.get_labels()
+        .iter()
+        .map(|label| escape_label(&label.to_string()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Change in the set of instances of a browsed service type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrowseEvent {
+    /// A new instance showed up on the network.
+    Added(String),
+    /// An instance left, either with a goodbye or because its record expired.
+    Removed(String),
+    /// The SRV or TXT record of a known instance changed.
+    Updated(String),
+}
 
-        // 6. Domain: exactly 'local'
-        if parts[3] != "local" {
-            return Err("Domain should be 'local'".to_string());
+/// Whether lookups ask responders to answer by unicast (the QU bit, RFC 6762 §5.4).
+///
+/// Unicast answers spare every other host on the link from receiving them, but only the
+/// asker can cache them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryMode {
+    /// Every query asks for multicast answers.
+    #[default]
+    Multicast,
+    /// The first query asks for unicast answers, repeated queries for multicast ones.
+    UnicastFirst,
+    /// Every query asks for unicast answers.
+    Unicast,
+}
+
+impl QueryMode {
+    // whether a query sets the QU bit, `first` for the first query of a lookup or browse
+    pub(crate) fn unicast(self, first: bool) -> bool {
+        match self {
+            QueryMode::Multicast => false,
+            QueryMode::UnicastFirst => first,
+            QueryMode::Unicast => true,
         }
+    }
+}
 
-        Ok(())
+/// How many records we multicast in answers to queries and how many were held back
+/// because the same record already went out on the interface less than a second ago.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MulticastStats {
+    pub sent: u64,
+    pub suppressed: u64,
+}
+
+/// Handle to a registered instance which follows it through renames after name conflicts.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    id: u64,
+    instance: watch::Receiver<Instance>,
+}
+
+impl Registration {
+    pub(crate) fn new(id: u64, instance: watch::Receiver<Instance>) -> Self {
+        Registration { id, instance }
+    }
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+    /// The name the instance is currently registered under.
+    pub fn name(&self) -> String {
+        self.instance.borrow().name().to_string()
+    }
+    pub fn instance(&self) -> Instance {
+        self.instance.borrow().clone()
+    }
+    /// Waits for the instance to be renamed, `None` once it is no longer registered.
+    pub async fn renamed(&mut self) -> Option<String> {
+        let name = self.name();
+        // metadata updates change the instance too, only a new name counts here
+        loop {
+            self.instance.changed().await.ok()?;
+            if self.name() != name {
+                return Some(self.name());
+            }
+        }
     }
 }
 
 // instance will be hashed in hashsets using the value of their name only
+// instances are the same if their names are, which compare case-insensitively like any DNS name
 impl PartialEq for Instance {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        self.name.to_lowercase() == other.name.to_lowercase()
     }
 }
 impl Eq for Instance {}
 
 impl std::hash::Hash for Instance {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.name.hash(state);
+        self.name.to_lowercase().hash(state);
     }
 }
 
+// variant names follow the DNS record type mnemonics
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum QueryType {
     PTR,
@@ -165,11 +432,12 @@ pub struct Query {
     pub qtype: QueryType,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ResponseInner {
     PTR(String),
     SRV { port: u16, target: String },
-    TXT { strings: Vec<String> },
+    TXT(TxtRecord),
     A { address: Ipv4Addr },
     AAAA { address: Ipv6Addr },
 }
@@ -178,14 +446,14 @@ pub enum ResponseInner {
 impl<'a> From<ResponseInner> for RData<'a> {
     fn from(response: ResponseInner) -> Self {
         match response {
-            ResponseInner::PTR(ptr) => RData::PTR(PTR(Name::new_unchecked(&ptr).into_owned())),
+            ResponseInner::PTR(ptr) => RData::PTR(PTR(parse_name(&ptr))),
             ResponseInner::SRV { port, target } => RData::SRV(SRV {
                 priority: 0,
                 weight: 0,
                 port,
-                target: Name::new_unchecked(&target).into_owned(),
+                target: parse_name(&target),
             }),
-            ResponseInner::TXT { strings } => RData::TXT(super::form_text_record(&strings)),
+            ResponseInner::TXT(record) => RData::TXT(record.to_txt()),
             ResponseInner::A { address } => RData::A(A {
                 address: address.into(),
             }),
@@ -200,6 +468,9 @@ impl<'a> From<ResponseInner> for RData<'a> {
 pub struct Response {
     pub inner: ResponseInner,
     pub ends_at: SystemTime,
+    pub received_at: SystemTime,
+    // RFC 6762 §10.2: the sender owns every record of this name and type
+    pub cache_flush: bool,
 }
 
 impl Hash for Response {
@@ -215,10 +486,23 @@ impl PartialEq for Response {
 }
 impl Eq for Response {}
 
+// signals delivered by the listener to an instance that is still probing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeSignal {
+    // a response on the link already carries records for the probed name
+    Conflict,
+    // a simultaneous probe from another host won the tie-break (RFC 6762 §8.2)
+    Lost,
+    // the interface with the index changed, an owned name has to be probed on it again (RFC 6762 §13)
+    Reprobe(u32),
+}
+
 #[derive(Debug)]
 pub struct ChannelMessage {
     pub ip: SocketAddr,
     pub bytes: Vec<u8>,
+    // index of the interface the packet arrived on or goes out of, 0 for any
+    pub interface: u32,
 }
 
 // write test to see if insert same query response twice duplicated by the cache or not.
@@ -242,10 +526,99 @@ mod tests {
         let response = Response {
             inner: ResponseInner::PTR("example.local".to_string()),
             ends_at: SystemTime::now(),
+            received_at: SystemTime::now(),
+            cache_flush: false,
         };
 
         cache.insert(query.clone(), response.clone(), 5).await;
         cache.insert(query, response, 10).await;
         assert_eq!(cache.iter().await.count(), 1); // Should still be 1
     }
+
+    #[test]
+    fn test_instance_renamed() {
+        let instance = Instance::new(
+            "tv._homecast._tcp.local".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        let second = instance.renamed();
+        assert_eq!(second.name(), "tv-2._homecast._tcp.local");
+        assert_eq!(second.renamed().name(), "tv-3._homecast._tcp.local");
+        // a trailing number that isn't a rename suffix is kept as part of the name
+        let instance = Instance::new(
+            "tv-1._homecast._tcp.local".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        assert_eq!(instance.renamed().name(), "tv-1-2._homecast._tcp.local");
+    }
+
+    #[test]
+    fn test_instance_errors() {
+        let result = Instance::new("tv._homecast._tcp.local".to_string(), 0, TxtRecord::new());
+        assert!(matches!(result, Err(Error::InvalidInstance(_))));
+        let result = Instance::break_instance_str("tv.local");
+        assert!(matches!(result, Err(Error::InvalidInstance(_))));
+        let result = Instance::new(
+            format!("{}._homecast._tcp.local", "x".repeat(64)),
+            8080,
+            TxtRecord::new(),
+        );
+        assert!(matches!(result, Err(Error::InvalidInstance(_))));
+        // empty labels aren't dropped silently
+        for name in [
+            "tv.._homecast._tcp.local",
+            ".tv._homecast._tcp.local",
+            "tv._homecast.._tcp.local",
+        ] {
+            let result = Instance::new(name.to_string(), 8080, TxtRecord::new());
+            assert!(matches!(result, Err(Error::InvalidInstance(_))));
+        }
+        // the domain is compared case-insensitively like any other name
+        let instance = Instance::new(
+            "tv._homecast._tcp.LOCAL".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        assert_eq!(instance.domain(), "local");
+        assert_eq!(instance.name(), "tv._homecast._tcp.local");
+        // a subtype is a single label
+        let result = Instance::new("tv._homecast._tcp.local".to_string(), 80, TxtRecord::new())
+            .unwrap()
+            .with_subtypes(["_a.b"]);
+        assert!(matches!(result, Err(Error::InvalidInstance(_))));
+    }
+
+    #[test]
+    fn test_instance_label_with_spaces_and_dots() {
+        let instance = Instance::new(
+            "Living Room TV v1.2._homecast._tcp.local".to_string(),
+            8080,
+            TxtRecord::new(),
+        )
+        .unwrap();
+        assert_eq!(instance.label(), "Living Room TV v1.2");
+        assert_eq!(instance.service_type(), "_homecast._tcp.local");
+        assert_eq!(
+            instance.name(),
+            r"Living Room TV v1\.2._homecast._tcp.local"
+        );
+        // the escaped form names the same instance
+        let escaped = Instance::new(instance.name().to_string(), 8080, TxtRecord::new()).unwrap();
+        assert_eq!(escaped, instance);
+
+        // on the wire the label stays a single one
+        let qname = instance.qname();
+        assert_eq!(qname.get_labels().len(), 4);
+        assert_eq!(display_name(&qname), instance.name());
+        assert_eq!(parse_name(instance.name()), qname);
+        assert_eq!(
+            Instance::break_instance_str(instance.name()).unwrap(),
+            "_homecast._tcp.local"
+        );
+    }
 }
@@ -0,0 +1,127 @@
+use super::announcer::Announcer;
+use super::interface::{self, InterfaceFilter};
+use super::listener::Listener;
+use super::prober::Prober;
+use super::register::Registry;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+// changes come in bursts (link up, then one address after another), wait for them to settle
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+// socket receiving link and address notifications of the kernel
+fn netlink_socket() -> io::Result<AsyncFd<OwnedFd>> {
+    // SAFETY: plain socket calls, the descriptor is owned right after it was created and
+    // stays open for as long as the AsyncFd holding it
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        if libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(AsyncFd::register(fd)?)
+    }
+}
+
+// Waits for the next notification, its content doesn't matter since we compare snapshots anyway.
+async fn notified(socket: &AsyncFd<OwnedFd>) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let mut guard = socket.readable().await?;
+        // SAFETY: the buffer outlives the call and its length is passed along
+        let read = guard.try_io(|fd| unsafe {
+            let len = libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            );
+            if len < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+        match read {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+// Follows interface and address changes on Linux (RFC 6762 §13): joins the group on new
+// interfaces, says goodbye for lost addresses and probes and announces our records again
+// on the interfaces that changed.
+pub fn spawn(
+    filter: InterfaceFilter,
+    registry: Registry,
+    prober: Prober,
+    announcer: Arc<Announcer>,
+    listener: Arc<Listener>,
+) -> io::Result<JoinHandle<()>> {
+    let socket = netlink_socket()?;
+    Ok(tokio::spawn(async move {
+        let mut known = filter.select(interface::list());
+        loop {
+            if let Err(e) = notified(&socket).await {
+                eprintln!("Stopped watching network changes: {}", e);
+                break;
+            }
+            // swallow the rest of the burst
+            sleep(SETTLE_DELAY).await;
+            while let Ok(Ok(())) = timeout(Duration::ZERO, notified(&socket)).await {}
+
+            let current = filter.select(interface::list());
+            let changes = interface::diff(&known, &current);
+            listener.sync_links(&current);
+            known = current;
+            if !registry.owned().is_empty() {
+                for gone in &changes.removed {
+                    if let Err(e) = announcer.goodbye_addresses(gone).await {
+                        eprintln!("Failed to send goodbye on {}: {}", gone.name, e);
+                    }
+                }
+            }
+            for interface in changes.added {
+                registry.reprobe(interface.index);
+                // the host name answers for the addresses even without any instance registered
+                let prober = prober.clone();
+                let announcer = announcer.clone();
+                tokio::spawn(async move {
+                    match prober.probe_host(&interface).await {
+                        Ok(true) => announcer.announce_host(&interface),
+                        Ok(false) => eprintln!(
+                            "Host name conflict for {} on {}",
+                            super::mdns_hostname(),
+                            interface.name
+                        ),
+                        Err(e) => {
+                            eprintln!("Failed to probe host name on {}: {}", interface.name, e)
+                        }
+                    }
+                });
+            }
+        }
+    }))
+}